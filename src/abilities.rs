@@ -0,0 +1,189 @@
+use common::Vec2;
+use components::{World, ID};
+use controller::{Controller, ControlInput};
+
+// Extra moves the player can be given, on top of what its controller does.
+// Having the component is having the ability, so pickups and the shell grant them.
+
+// Jumps in mid-air, refilled on landing
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AirJumps {
+  pub count: u32,
+  #[serde(skip)]
+  pub left: u32,
+}
+
+impl Default for AirJumps {
+  fn default() -> AirJumps {
+    AirJumps {
+      count: 1,
+      left: 0,
+    }
+  }
+}
+
+// A quick straight move in the aimed direction (or facing, with no aim)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Dash {
+  pub distance: f64,
+  // Seconds the dash takes to cover the distance
+  pub duration: f64,
+  // Seconds from the start of one dash to the next
+  pub cooldown: f64,
+  // Dashes go straight instead of falling
+  pub suspend_gravity: bool,
+  #[serde(skip)]
+  pub state: DashState,
+}
+
+impl Default for Dash {
+  fn default() -> Dash {
+    Dash {
+      distance: 12.,
+      duration: 0.15,
+      cooldown: 0.5,
+      suspend_gravity: true,
+      state: DashState::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DashState {
+  // Direction and time left of the dash in progress
+  pub active: Option<(Vec2, f64)>,
+  pub cooldown: f64,
+  // Last horizontal direction walked, -1 or 1 (0 before any)
+  pub facing: f64,
+}
+
+// Abilities as pickups and the shell name them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Ability {
+  AirJumps(u32),
+  Dash,
+}
+
+// Everything the abilities look at for one step
+pub struct AbilityInput {
+  pub control: ControlInput,
+  pub dash_pressed: bool,
+  // Held up/down, -1 to 1
+  pub aim: f64,
+}
+
+impl World {
+  pub fn grant(&mut self, id: ID, ability: Ability) {
+    match ability {
+      Ability::AirJumps(count) => {
+        self.air_jumps.insert(id, AirJumps { count: count, left: count });
+      },
+      Ability::Dash => {
+        self.dashes.entry(id).or_insert_with(Dash::default);
+      },
+    }
+  }
+
+  pub fn revoke(&mut self, id: ID, ability: Ability) {
+    match ability {
+      Ability::AirJumps(_) => {
+        self.air_jumps.remove(&id);
+      },
+      Ability::Dash => {
+        self.dashes.remove(&id);
+        self.weightless.remove(&id);
+      },
+    }
+  }
+}
+
+// Jumps that the controller didn't use on the ground or a wall become air jumps.
+// Runs after the controller's update for the same step.
+pub fn air_jump(jumps: &mut AirJumps, controller: &mut Controller, input: &ControlInput, grounded: bool, vel: &mut Vec2) {
+  if grounded {
+    jumps.left = jumps.count;
+    return;
+  }
+  if input.jump_pressed && controller.state.buffered.is_some() && jumps.left > 0 {
+    jumps.left -= 1;
+    vel.y = controller.jump_speed;
+    controller.state.buffered = None;
+    controller.state.jumping = true;
+  }
+}
+
+// Returns whether the body is dashing, the controller sits out while it is
+pub fn dash(dash: &mut Dash, controller: &Controller, input: &AbilityInput, vel: &mut Vec2, dt: f64) -> bool {
+  if input.control.walk != 0. {
+    dash.state.facing = input.control.walk.signum();
+  }
+  dash.state.cooldown -= dt;
+  if input.dash_pressed && dash.state.cooldown <= 0. && dash.state.active.is_none() {
+    let facing = if dash.state.facing == 0. { 1. } else { dash.state.facing };
+    let aim = Vec2::new(input.control.walk, input.aim);
+    let direction = if aim.x == 0. && aim.y == 0. { Vec2::new(facing, 0.) } else { aim.normalize() };
+    dash.state.active = Some((direction, dash.duration));
+    dash.state.cooldown = dash.cooldown;
+  }
+  match dash.state.active {
+    Some((direction, left)) => {
+      if left > 0. {
+        *vel = direction * (dash.distance / dash.duration);
+        dash.state.active = Some((direction, left - dt));
+        true
+      } else {
+        // Come out of it no faster than running
+        *vel = direction * controller.run_speed.min(dash.distance / dash.duration);
+        dash.state.active = None;
+        false
+      }
+    },
+    None => false,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn air_jumps_and_dashes() {
+    let dt = 0.01;
+    let c = Controller::default();
+    let mut controller = c.clone();
+    let mut jumps = AirJumps { count: 1, left: 0 };
+    let mut vel = Vec2::new(0., -10.);
+    let press = ControlInput { jump_pressed: true, jump_held: true, ..ControlInput::default() };
+
+    // Nothing left until landing refills it
+    controller.update(&press, false, None, &mut vel, dt);
+    air_jump(&mut jumps, &mut controller, &press, false, &mut vel);
+    assert_eq!(vel.y, -10.);
+    air_jump(&mut jumps, &mut controller, &ControlInput::default(), true, &mut vel);
+    controller.state.since_grounded = 1.;
+    controller.update(&press, false, None, &mut vel, dt);
+    air_jump(&mut jumps, &mut controller, &press, false, &mut vel);
+    assert_eq!(vel.y, c.jump_speed);
+    assert_eq!(jumps.left, 0);
+
+    // Dashes up and to the left for the whole distance, then waits for the cooldown
+    let mut d = Dash::default();
+    let input = AbilityInput {
+      control: ControlInput { walk: -1., ..ControlInput::default() },
+      dash_pressed: true,
+      aim: 1.,
+    };
+    let mut pos = Vec2::new(0., 0.);
+    let mut steps = 0;
+    while dash(&mut d, &c, &input, &mut vel, dt) {
+      pos += vel * dt;
+      steps += 1;
+    }
+    assert!((steps as f64 * dt - d.duration).abs() < dt);
+    assert!((pos.x + pos.y).abs() < 1e-9 && pos.x < 0.);
+    assert!((pos.x.hypot(pos.y) - d.distance).abs() < d.distance / 10.);
+    assert!(!dash(&mut d, &c, &input, &mut vel, dt));
+  }
+}
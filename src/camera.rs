@@ -1,4 +1,5 @@
 use common::{Vec2};
+use components::{World, ID};
 
 use std::f64;
 use sdl2::rect::Rect;
@@ -55,6 +56,46 @@ impl Camera {
   }
 }
 
+// Keeps a camera on an entity, per axis. Axes it doesn't follow are left to the camera controls.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Follow {
+  pub target: ID,
+  pub x: bool,
+  pub y: bool,
+  // Set while something else holds the camera, like a camera zone
+  #[serde(default)]
+  pub paused: bool,
+}
+
+impl Follow {
+  // Side to side only, up and down stays with the controls
+  pub fn x(target: ID) -> Follow {
+    Follow {
+      target: target,
+      x: true,
+      y: false,
+      paused: false,
+    }
+  }
+}
+
+// Once per frame, after everything has moved
+pub fn camera_follow_system(w: &mut World, _: f64) {
+  for (id, follow) in &w.follows {
+    if follow.paused {
+      continue;
+    }
+    if let (Some(camera), Some(target)) = (w.cameras.get_mut(id), w.positions.get(&follow.target)) {
+      if follow.x {
+        camera.pos.x = target.x;
+      }
+      if follow.y {
+        camera.pos.y = target.y;
+      }
+    }
+  }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -79,4 +120,25 @@ mod tests {
         assert!(dr3.left() == -30);
         assert!(dr3.top() == 110);
     }
+
+    #[test]
+    fn follows_only_the_axes_asked_for() {
+        let mut w = World::new();
+        let camera = w.new_camera(10., Vec2::new(0., 0.), Vec2::new(100., 100.));
+        let target = w.new_entity();
+        w.positions.insert(target, Vec2::new(5., 7.));
+        w.follows.insert(camera, Follow::x(target));
+        camera_follow_system(&mut w, 0.);
+        assert_eq!(w.cameras[&camera].pos, Vec2::new(5., 0.));
+
+        // The controls keep the other axis, and a paused follow leaves both alone
+        w.cameras.get_mut(&camera).unwrap().pos.y = 3.;
+        w.positions.insert(target, Vec2::new(9., 9.));
+        camera_follow_system(&mut w, 0.);
+        assert_eq!(w.cameras[&camera].pos, Vec2::new(9., 3.));
+        w.follows.get_mut(&camera).unwrap().paused = true;
+        w.positions.insert(target, Vec2::new(0., 0.));
+        camera_follow_system(&mut w, 0.);
+        assert_eq!(w.cameras[&camera].pos, Vec2::new(9., 3.));
+    }
 }
@@ -1,19 +1,23 @@
 extern crate nalgebra as na;
 extern crate sdl2;
 
+use std::f64;
 use std::collections::HashSet;
 use self::sdl2::keyboard::{Keycode, Mod};
 use self::sdl2::mouse::{MouseState, MouseButton};
 
 pub type Vec2 = self::na::Vector2<f64>;
 
+// How far into a surface (as a fraction of the sweep) still counts as touching it
+const SWEEP_EPSILON: f64 = 1e-9;
+
 macro_rules! rect(
     ($x:expr, $y:expr, $w:expr, $h:expr) => (
         Rect::new($x as i32, $y as i32, $w as u32, $h as u32)
     )
 );
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AABB {
   pub center: Vec2,
   pub half_size: Vec2,
@@ -34,9 +38,25 @@ impl AABB {
   pub fn bottom_left(&self) -> Vec2 {
     self.center - self.half_size
   }
-  // pub fn top_right(&self) -> Vec2 {
-  //   self.center + self.half_size
-  // }
+  pub fn expanded(&self, margin: f64) -> AABB {
+    AABB {
+      center: self.center,
+      half_size: self.half_size + Vec2::new(margin, margin),
+    }
+  }
+  // Smallest box containing both
+  pub fn union(&self, other: &AABB) -> AABB {
+    let bl = Vec2::new(
+      self.bottom_left().x.min(other.bottom_left().x),
+      self.bottom_left().y.min(other.bottom_left().y));
+    let tr = Vec2::new(
+      self.top_right().x.max(other.top_right().x),
+      self.top_right().y.max(other.top_right().y));
+    AABB::new((bl + tr) / 2., (tr - bl) / 2.)
+  }
+  pub fn top_right(&self) -> Vec2 {
+    self.center + self.half_size
+  }
   // pub fn bottom_right(&self) -> Vec2 {
   //   Vec2::new(self.center.x + self.half_size.x, self.center.y - self.half_size.y)
   // }
@@ -82,6 +102,72 @@ impl AABB {
       _ => None
     }
   }
+
+  // Time of impact, as a fraction of `d`, of this box moving by `d` into a static `other`.
+  // Boxes that already overlap at the start aren't a hit, resting contact (moving into
+  // a surface you're touching) is a hit at 0.
+  pub fn sweep(&self, d: Vec2, other: &AABB) -> Option<f64> {
+    let reach = self.half_size + other.half_size;
+    let mut entry = f64::NEG_INFINITY;
+    let mut exit = f64::INFINITY;
+    for axis in 0..2 {
+      let (p, v, c, r) = (self.center[axis], d[axis], other.center[axis], reach[axis]);
+      if v == 0. {
+        if (p - c).abs() >= r {
+          return None;
+        }
+      } else {
+        let t1 = (c - r - p) / v;
+        let t2 = (c + r - p) / v;
+        entry = entry.max(t1.min(t2));
+        exit = exit.min(t1.max(t2));
+      }
+    }
+    if entry < exit && entry >= -SWEEP_EPSILON && entry <= 1. {
+      Some(entry.max(0.))
+    } else {
+      None
+    }
+  }
+}
+
+// Walkable surface inside a box, rising in a straight line from the left edge to the right.
+// `left` and `right` are heights above the bottom of the box, as a fraction of its height.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Slope {
+  pub left: f64,
+  pub right: f64,
+}
+impl Slope {
+  pub fn new(left: f64, right: f64) -> Slope {
+    Slope {
+      left: left,
+      right: right,
+    }
+  }
+  // Rise per unit of x, flat for a box with no width
+  pub fn gradient(&self, aabb: &AABB) -> f64 {
+    if aabb.half_size.x <= 0. {
+      return 0.;
+    }
+    (self.right - self.left) * aabb.half_size.y / aabb.half_size.x
+  }
+  pub fn normal(&self, aabb: &AABB) -> Vec2 {
+    Vec2::new(-self.gradient(aabb), 1.).normalize()
+  }
+  pub fn height_at(&self, aabb: &AABB, x: f64) -> f64 {
+    let left = aabb.center.x - aabb.half_size.x;
+    let x = x.max(left).min(aabb.center.x + aabb.half_size.x);
+    aabb.bottom_left().y + self.left * aabb.half_size.y * 2. + self.gradient(aabb) * (x - left)
+  }
+  // Highest point of the surface under the span x0..x1, if the span overlaps the box
+  pub fn highest(&self, aabb: &AABB, x0: f64, x1: f64) -> Option<f64> {
+    let (left, right) = (aabb.center.x - aabb.half_size.x, aabb.center.x + aabb.half_size.x);
+    if x1 <= left || x0 >= right {
+      return None;
+    }
+    Some(self.height_at(aabb, x0).max(self.height_at(aabb, x1)))
+  }
 }
 
 pub struct InputState {
@@ -8,16 +8,40 @@ use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-use common::{Vec2, AABB};
+use common::{Vec2, AABB, Slope};
 use render::Sprite;
-use camera::Camera;
-use platforms::MoverBlock;
-
-#[derive(Serialize, Deserialize, Debug)]
+use camera::{Camera, Follow};
+use platforms::{MoverBlock, Conveyor, Crumbling, Launcher};
+use prefab::{Prefab, PrefabInstance, prefab_path};
+use hierarchy::{Parent, children_of};
+use events::EventQueue;
+use tilemap::Tilemap;
+use triggers::Trigger;
+use physics::{PhysicsSettings, Material};
+use controller::Controller;
+use abilities::{AirJumps, Dash};
+use water::{Liquid, Submersion};
+use logic::{Logic, Door};
+use joints::Joint;
+use layers::{LayerBits, LayerTable, WORLD, world_layer, all_layers, interacts};
+
+// Where the built-in player starts
+const PLAYER_START: (f64, f64) = (8., 4.);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum PlayerAction {
   MoveLeft,
   MoveRight,
   Jump,
+  // Held down, lets go of a jump early when missing
+  JumpHeld,
+  DropThrough,
+  Dash,
+  // Held, aims the dash
+  LookUp,
+  LookDown,
+  // Flips levers
+  Interact,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -33,17 +57,57 @@ pub enum CameraAction {
 // Components
 pub type Position = Vec2;
 
-pub type Collision = AABB;
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Collision {
+  pub center: Vec2,
+  pub half_size: Vec2,
+  // Jump-through platform, only solid when landed on from above
+  #[serde(default)]
+  pub one_way: bool,
+  // Ramp instead of a full box, only its surface is solid from above
+  #[serde(default)]
+  pub slope: Option<Slope>,
+  // Never pushes anything, only reports overlaps
+  #[serde(default)]
+  pub sensor: bool,
+  #[serde(default="world_layer")]
+  pub layer: LayerBits,
+  // Layers this collider collides with
+  #[serde(default="all_layers")]
+  pub mask: LayerBits,
+}
+impl Collision {
+  pub fn new(center: Vec2, half_size: Vec2) -> Collision {
+    Collision {
+      center: center,
+      half_size: half_size,
+      one_way: false,
+      slope: None,
+      sensor: false,
+      layer: world_layer(),
+      mask: all_layers(),
+    }
+  }
+  pub fn interacts_with(&self, other: &Collision) -> bool {
+    interacts(self.layer, self.mask, other.layer, other.mask)
+  }
+  pub fn offset(&self, v: Vec2) -> AABB {
+    AABB::new(self.center + v, self.half_size)
+  }
+}
 
 pub type Velocity = Vec2;
 
 type Groundable = bool;
 
+// Bodies without one weigh 1, colliders that don't move can't be pushed at all
+pub type Mass = f64;
+
 pub type PlayerActions = Vec<PlayerAction>;
 pub type CameraActions = Vec<CameraAction>;
 
 
-type ID = usize;
+pub type ID = usize;
 type EMap<T> = HashMap<ID, T>;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -60,8 +124,61 @@ pub struct World {
   pub velocities: EMap<Velocity>,
   pub groundables: HashMap<ID, Groundable>,
   pub cameras: HashMap<ID, Camera>,
+  // Cameras that keep up with an entity
+  #[serde(default)]
+  pub follows: EMap<Follow>,
   #[serde(default)]
   pub mover_blocks: HashMap<ID, MoverBlock>,
+  #[serde(default)]
+  pub conveyors: EMap<Conveyor>,
+  #[serde(default)]
+  pub crumbling: EMap<Crumbling>,
+  #[serde(default)]
+  pub launchers: EMap<Launcher>,
+  #[serde(default)]
+  pub prefab_instances: EMap<PrefabInstance>,
+  #[serde(default)]
+  pub parents: EMap<Parent>,
+  #[serde(default)]
+  pub names: EMap<String>,
+  #[serde(default)]
+  pub masses: EMap<Mass>,
+  // Positioned by their bottom left corner
+  #[serde(default)]
+  pub tilemaps: EMap<Tilemap>,
+  #[serde(default)]
+  pub triggers: EMap<Trigger>,
+  // Where hazards send bodies back to
+  #[serde(default)]
+  pub respawns: EMap<Position>,
+  // Where bodies were when physics first moved them, for respawning the ones
+  // the level didn't place from a prefab
+  #[serde(skip)]
+  pub starts: EMap<Position>,
+  #[serde(default)]
+  pub layers: LayerTable,
+  #[serde(default)]
+  pub physics: PhysicsSettings,
+  // On bodies it's how they move, on obstacles and tilemaps it's their surface
+  #[serde(default)]
+  pub materials: EMap<Material>,
+  #[serde(default)]
+  pub controllers: EMap<Controller>,
+  #[serde(default)]
+  pub air_jumps: EMap<AirJumps>,
+  #[serde(default)]
+  pub dashes: EMap<Dash>,
+  // Sensors with one are water volumes
+  #[serde(default)]
+  pub liquids: EMap<Liquid>,
+  // Switches and gates, wired to each other and to doors and movers by ID
+  #[serde(default)]
+  pub logic: EMap<Logic>,
+  #[serde(default)]
+  pub doors: EMap<Door>,
+  // Keyed by the joint's own entity, which names the two it ties
+  #[serde(default)]
+  pub joints: EMap<Joint>,
 
   pub entities: HashSet<ID>,
   next: ID,
@@ -80,6 +197,42 @@ pub struct World {
   // This is truly ephemeral state
   #[serde(skip)]
   pub statics_collisions: HashSet<ID>,
+  #[serde(skip)]
+  pub contacts: HashSet<(ID, ID)>,
+  #[serde(skip)]
+  pub events: EventQueue,
+  // Seconds left during which a body falls through one-way platforms
+  #[serde(skip)]
+  pub drop_through: EMap<f64>,
+  // Normal of the surface each grounded body stands on
+  #[serde(skip)]
+  pub ground_normals: EMap<Vec2>,
+  // Side (-1 left, 1 right) of a wall each body pushed against on the last step
+  #[serde(skip)]
+  pub wall_contacts: EMap<f64>,
+  // What each grounded body stood on at the end of the last step
+  #[serde(skip)]
+  pub supports: EMap<ID>,
+  // (sensor, body) pairs overlapping as of the last fixed step
+  #[serde(skip)]
+  pub sensor_contacts: HashSet<(ID, ID)>,
+  // Set by a level exit, the main loop loads it at the end of the frame
+  #[serde(skip)]
+  pub next_level: Option<String>,
+  // Debug render skips colliders whose layers are all hidden
+  #[serde(skip)]
+  pub hidden_layers: LayerBits,
+  // Bodies a controller steered this step, it slows them on the ground instead of friction
+  #[serde(skip)]
+  pub steered: HashSet<ID>,
+  // Bodies gravity leaves alone this step, like dashing ones
+  #[serde(skip)]
+  pub weightless: HashSet<ID>,
+  // The liquid each body was in on the last step
+  #[serde(skip)]
+  pub submerged: EMap<Submersion>,
+  #[serde(skip)]
+  prefabs: HashMap<String, Prefab>,
 }
 
 impl World {
@@ -93,7 +246,29 @@ impl World {
       velocities: HashMap::new(),
       groundables: HashMap::new(),
       cameras: HashMap::new(),
+      follows: EMap::new(),
       mover_blocks: EMap::new(),
+      conveyors: EMap::new(),
+      crumbling: EMap::new(),
+      launchers: EMap::new(),
+      prefab_instances: EMap::new(),
+      parents: EMap::new(),
+      names: EMap::new(),
+      masses: EMap::new(),
+      tilemaps: EMap::new(),
+      triggers: EMap::new(),
+      respawns: EMap::new(),
+      starts: EMap::new(),
+      layers: LayerTable::default(),
+      physics: PhysicsSettings::default(),
+      materials: EMap::new(),
+      controllers: EMap::new(),
+      air_jumps: EMap::new(),
+      dashes: EMap::new(),
+      liquids: EMap::new(),
+      logic: EMap::new(),
+      doors: EMap::new(),
+      joints: EMap::new(),
 
       player_actions: HashMap::new(),
       camera_actions: HashMap::new(),
@@ -106,6 +281,19 @@ impl World {
       current_tilemap: 0,
 
       statics_collisions: HashSet::new(),
+      contacts: HashSet::new(),
+      events: EventQueue::new(),
+      drop_through: EMap::new(),
+      ground_normals: EMap::new(),
+      wall_contacts: EMap::new(),
+      supports: EMap::new(),
+      sensor_contacts: HashSet::new(),
+      next_level: None,
+      hidden_layers: 0,
+      steered: HashSet::new(),
+      weightless: HashSet::new(),
+      submerged: EMap::new(),
+      prefabs: HashMap::new(),
     }
   }
 
@@ -125,6 +313,12 @@ impl World {
     for (_, ref mut sprite) in world.sprites.iter_mut() {
       sprite.reload_assets(renderer);
     }
+    for (_, ref mut tilemap) in world.tilemaps.iter_mut() {
+      if let Err(e) = tilemap.reload_assets() {
+        println!("Couldn't load tilemap {}: {}", tilemap.source_path, e);
+      }
+    }
+    world.refresh_prefab_instances();
     world.alive = true;
 
     // println!("deserialized = {:?}", world);
@@ -138,33 +332,261 @@ impl World {
     id
   }
 
+  // Children are detached and stay where they are, so deleting the player doesn't
+  // take the camera with it. Joints holding on to the entity are deleted.
   pub fn delete_entity(&mut self, id: ID) {
+    if !self.entities.remove(&id) {
+      return;
+    }
+    for child in children_of(self, id) {
+      self.detach(child);
+    }
+    // Joints holding on to it go with it
+    let joints: Vec<ID> = self.joints.iter()
+      .filter(|&(_, joint)| joint.a == id || joint.b == id)
+      .map(|(joint, _)| *joint)
+      .collect();
+    for joint in joints {
+      self.delete_entity(joint);
+    }
     self.positions.remove(&id);
     self.sprites.remove(&id);
     self.collisions.remove(&id);
     self.velocities.remove(&id);
     self.groundables.remove(&id);
     self.cameras.remove(&id);
+    self.follows.remove(&id);
+    self.mover_blocks.remove(&id);
+    self.conveyors.remove(&id);
+    self.crumbling.remove(&id);
+    self.launchers.remove(&id);
+    self.supports.remove(&id);
+    self.prefab_instances.remove(&id);
+    self.parents.remove(&id);
+    self.names.remove(&id);
+    self.masses.remove(&id);
+    self.tilemaps.remove(&id);
+    self.drop_through.remove(&id);
+    self.ground_normals.remove(&id);
+    self.wall_contacts.remove(&id);
+    self.triggers.remove(&id);
+    self.respawns.remove(&id);
+    self.starts.remove(&id);
+    self.materials.remove(&id);
+    self.controllers.remove(&id);
+    self.air_jumps.remove(&id);
+    self.dashes.remove(&id);
+    self.weightless.remove(&id);
+    self.liquids.remove(&id);
+    self.submerged.remove(&id);
+    self.steered.remove(&id);
+    self.logic.remove(&id);
+    self.doors.remove(&id);
+    self.joints.remove(&id);
 
     self.player_actions.remove(&id);
     self.camera_actions.remove(&id);
+  }
 
-    self.entities.remove(&id);
+  pub fn find_by_name(&self, name: &str) -> Option<ID> {
+    self.names.iter()
+      .find(|&(_, n)| n == name)
+      .map(|(id, _)| *id)
+  }
+
+  // Names are unique, so giving a name that's already taken fails
+  pub fn set_name(&mut self, id: ID, name: &str) -> Result<(), String> {
+    if !self.entities.contains(&id) {
+      return Err(format!("No entity {}", id));
+    }
+    // resolve would read it as an ID
+    if name.parse::<ID>().is_ok() {
+      return Err(format!("Name {} is a number, it would be taken for an ID", name));
+    }
+    match self.find_by_name(name) {
+      Some(other) if other != id => Err(format!("Name {} is already used by {}", name, other)),
+      _ => {
+        self.names.insert(id, name.to_owned());
+        Ok(())
+      },
+    }
+  }
+
+  // Look up an entity by numeric ID or by name
+  pub fn resolve(&self, entity: &str) -> Option<ID> {
+    match entity.parse::<ID>() {
+      Ok(id) if self.entities.contains(&id) => Some(id),
+      Ok(_) => None,
+      Err(_) => self.find_by_name(entity),
+    }
+  }
+
+  // Where the level placed an entity from its prefab, or else where it started moving.
+  // The player always has one, the built-in spot if nothing else.
+  pub fn spawn_point(&mut self, id: ID) -> Option<Position> {
+    let instance = self.prefab_instances.get(&id).map(|i| (i.prefab.clone(), i.overrides.position));
+    let placed = match instance {
+      Some((_, Some(pos))) => Some(pos),
+      Some((name, None)) => self.load_prefab(&name).ok().and_then(|prefab| prefab.position),
+      None => None,
+    };
+    match placed.or_else(|| self.starts.get(&id).cloned()) {
+      None if id == self.current_player => Some(Position::new(PLAYER_START.0, PLAYER_START.1)),
+      point => point,
+    }
   }
 
   pub fn new_player(&mut self) -> ID {
+    let mut collision = Collision::new(Vec2::new(0., 0.), Vec2::new(1., 2.));
+    collision.layer = self.layers.bit("player").unwrap_or(WORLD);
+    self.instantiate_or_builtin("player", Prefab::default(), Prefab {
+      position: Some(Position::new(PLAYER_START.0, PLAYER_START.1)),
+      collision: Some(collision),
+      velocity: Some(Velocity::new(0., 0.)),
+      groundable: Some(false),
+      player: Some(true),
+      ..Prefab::default()
+    })
+  }
+
+  fn load_prefab(&mut self, name: &str) -> Result<Prefab, io::Error> {
+    if !self.prefabs.contains_key(name) {
+      let prefab = Prefab::from_file(Path::new(&prefab_path(name)))?;
+      self.prefabs.insert(name.to_owned(), prefab);
+    }
+    Ok(self.prefabs[name].clone())
+  }
+
+  pub fn instantiate_prefab(&mut self, name: &str, overrides: Prefab) -> Result<ID, io::Error> {
     let id = self.new_entity();
-    self.positions.insert(id, Position::new(8., 4.));
-    // self.sprites.insert(id, Sprite {
-    //
-    // };
-    self.collisions.insert(id, Collision::new(
-      Vec2::new(0., 0.), Vec2::new(1., 2.))
-    );
-    self.velocities.insert(id, Velocity::new(0., 0.));
-    self.groundables.insert(id, false);
-    self.player_actions.insert(id, Vec::new());
-    id
+    self.prefab_instances.insert(id, PrefabInstance {
+      prefab: name.to_owned(),
+      overrides: overrides,
+    });
+    match self.apply_prefab(id, false) {
+      Ok(()) => Ok(id),
+      Err(e) => {
+        self.delete_entity(id);
+        Err(e)
+      },
+    }
+  }
+
+  // The constructors below use their prefab when it loads, and the same components
+  // built in (not tied to any prefab) when it doesn't, so they never need the assets
+  fn instantiate_or_builtin(&mut self, name: &str, overrides: Prefab, builtin: Prefab) -> ID {
+    match self.instantiate_prefab(name, overrides.clone()) {
+      Ok(id) => id,
+      Err(e) => {
+        println!("Couldn't load prefab {}, using the built-in one: {}", name, e);
+        let id = self.new_entity();
+        self.insert_components(id, builtin.with_overrides(&overrides), false);
+        id
+      },
+    }
+  }
+
+  // Rebuild the components of an instance from its prefab and overrides.
+  // When keep_position is set, an entity that already has a position stays where it is
+  // (so reloading a level doesn't teleport everything back to its spawn point)
+  fn apply_prefab(&mut self, id: ID, keep_position: bool) -> Result<(), io::Error> {
+    let (name, overrides) = match self.prefab_instances.get(&id) {
+      Some(instance) => (instance.prefab.clone(), instance.overrides.clone()),
+      None => return Ok(()),
+    };
+    let components = self.load_prefab(&name)?.with_overrides(&overrides);
+    self.insert_components(id, components, keep_position);
+    Ok(())
+  }
+
+  fn insert_components(&mut self, id: ID, components: Prefab, keep_position: bool) {
+    if !(keep_position && self.positions.contains_key(&id)) {
+      if let Some(position) = components.position {
+        self.positions.insert(id, position);
+      }
+    }
+    if let Some(collision) = components.collision {
+      self.collisions.insert(id, collision);
+    }
+    if let Some(velocity) = components.velocity {
+      self.velocities.insert(id, velocity);
+    }
+    if let Some(groundable) = components.groundable {
+      self.groundables.insert(id, groundable);
+    }
+    if components.player == Some(true) {
+      self.player_actions.entry(id).or_insert_with(Vec::new);
+    }
+    if let Some(mut mover_block) = components.mover_block {
+      // Wiring is per instance, the prefab has none
+      if mover_block.input.is_none() {
+        mover_block.input = self.mover_blocks.get(&id).and_then(|old| old.input);
+      }
+      self.mover_blocks.insert(id, mover_block);
+    }
+    if let Some(conveyor) = components.conveyor {
+      self.conveyors.insert(id, conveyor);
+    }
+    if let Some(crumbling) = components.crumbling {
+      self.crumbling.insert(id, crumbling);
+    }
+    if let Some(launcher) = components.launcher {
+      self.launchers.insert(id, launcher);
+    }
+    if let Some(mass) = components.mass {
+      self.masses.insert(id, mass);
+    }
+    if let Some(material) = components.material {
+      self.materials.insert(id, material);
+    }
+    if let Some(trigger) = components.trigger {
+      self.triggers.insert(id, trigger);
+    }
+    if let Some(controller) = components.controller {
+      self.controllers.insert(id, controller);
+    }
+    if let Some(air_jumps) = components.air_jumps {
+      self.air_jumps.insert(id, air_jumps);
+    }
+    if let Some(dash) = components.dash {
+      self.dashes.insert(id, dash);
+    }
+    if let Some(liquid) = components.liquid {
+      self.liquids.insert(id, liquid);
+    }
+    if let Some(mut logic) = components.logic {
+      // Levers stay where the level left them
+      if let Some(old) = self.logic.get(&id) {
+        if old.node == logic.node {
+          logic.on = old.on;
+        }
+      }
+      self.logic.insert(id, logic);
+    }
+    if let Some(mut door) = components.door {
+      if door.input.is_none() {
+        door.input = self.doors.get(&id).and_then(|old| old.input);
+      }
+      self.doors.insert(id, door);
+    }
+  }
+
+  // Edits to a prefab instance go in its overrides too, or the prefab undoes them on load
+  pub fn override_instance<F: FnOnce(&mut Prefab)>(&mut self, id: ID, edit: F) {
+    if let Some(instance) = self.prefab_instances.get_mut(&id) {
+      edit(&mut instance.overrides);
+    }
+  }
+
+  // Re-read every prefab from disk and reapply it to its instances
+  pub fn refresh_prefab_instances(&mut self) {
+    self.prefabs.clear();
+    let ids: Vec<ID> = self.prefab_instances.keys().cloned().collect();
+    for id in ids {
+      if let Err(e) = self.apply_prefab(id, true) {
+        println!("Couldn't apply prefab to {}: {}", id, e);
+      }
+    }
   }
 
   pub fn new_camera(&mut self, fovy: f64, pos: Vec2, screen_size: Vec2) -> ID {
@@ -186,26 +608,59 @@ impl World {
   }
 
   pub fn new_mover_block(&mut self, start: Vec2, end: Vec2, travel_time: f64) -> ID {
-    let id = self.new_entity();
-    self.mover_blocks.insert(id, MoverBlock {
-      start: start,
-      end: end,
-      travel_time: travel_time
-    });
-    self.positions.insert(id, start);
-    self.collisions.insert(id, Collision::new(Vec2::new(0., 0.), Vec2::new(4., 1.)));
-
-    id
+    self.instantiate_or_builtin("mover_block", Prefab {
+      position: Some(start),
+      mover_block: Some(MoverBlock {
+        start: start,
+        end: end,
+        travel_time: travel_time,
+        input: None,
+      }),
+      ..Prefab::default()
+    }, Prefab {
+      collision: Some(Collision::new(Vec2::new(0., 0.), Vec2::new(4., 1.))),
+      ..Prefab::default()
+    })
   }
 
   pub fn new_static_obstacle(&mut self, center: Vec2, size: Vec2) -> ID {
+    self.instantiate_or_builtin("obstacle", Prefab {
+      position: Some(center),
+      collision: Some(Collision::new(Vec2::new(0., 0.), size / 2.)),
+      ..Prefab::default()
+    }, Prefab::default())
+  }
+
+  pub fn new_sensor(&mut self, center: Vec2, size: Vec2) -> ID {
+    let mut collision = Collision::new(Vec2::new(0., 0.), size / 2.);
+    collision.sensor = true;
+    self.instantiate_or_builtin("sensor", Prefab {
+      position: Some(center),
+      collision: Some(collision),
+      ..Prefab::default()
+    }, Prefab::default())
+  }
+
+  pub fn new_water(&mut self, center: Vec2, size: Vec2) -> ID {
+    let mut collision = Collision::new(Vec2::new(0., 0.), size / 2.);
+    collision.sensor = true;
+    self.instantiate_or_builtin("water", Prefab {
+      position: Some(center),
+      collision: Some(collision),
+      ..Prefab::default()
+    }, Prefab {
+      liquid: Some(Liquid::default()),
+      ..Prefab::default()
+    })
+  }
+
+  pub fn new_tilemap(&mut self, path: &str, origin: Vec2) -> Result<ID, String> {
+    let tilemap = Tilemap::from_file(Path::new(path))?;
     let id = self.new_entity();
-    self.positions.insert(id, center);
-    self.collisions.insert(id, Collision::new(
-      Vec2::new(0., 0.), size / 2.,
-    ));
-    // self.sprites.insert(id, Sprite::new()
-    id
+    self.positions.insert(id, origin);
+    self.tilemaps.insert(id, tilemap);
+    self.current_tilemap = id;
+    Ok(id)
   }
 
   pub fn get_moving_entity(&self, id: ID) -> Option<(&Position, &Velocity)> {
@@ -222,8 +677,20 @@ impl World {
     }
   }
 
+  // Platforms that crumbled are written where they rest, their state isn't saved
+  // so they load solid and in place
+  pub fn to_json(&self) -> String {
+    let mut value = serde_json::to_value(&self).unwrap();
+    for (id, crumbling) in &self.crumbling {
+      if let Some(home) = crumbling.home() {
+        value["positions"][id.to_string()] = serde_json::to_value(home).unwrap();
+      }
+    }
+    serde_json::to_string(&value).unwrap()
+  }
+
   pub fn save(&self, filename: &str) -> Result<String, io::Error> {
-    let serialized = serde_json::to_string(&self).unwrap();
+    let serialized = self.to_json();
     println!("serialized = {}", serialized);
 
     let filename = format!("assets/{}.air", filename);
@@ -233,3 +700,16 @@ impl World {
     Ok("good job".to_owned())
   }
 }
+
+#[cfg(test)]
+impl World {
+  // A plain box body for tests, not on the ground yet
+  pub fn test_body(&mut self, pos: Vec2, vel: Vec2, half_size: Vec2) -> ID {
+    let id = self.new_entity();
+    self.positions.insert(id, pos);
+    self.collisions.insert(id, Collision::new(Vec2::new(0., 0.), half_size));
+    self.velocities.insert(id, vel);
+    self.groundables.insert(id, false);
+    id
+  }
+}
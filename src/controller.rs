@@ -0,0 +1,336 @@
+use std::f64;
+
+use common::Vec2;
+use components::Velocity;
+
+// How a player-driven body runs and jumps. Stored per entity, so different
+// characters (or the same one in different levels) can feel different.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Controller {
+  pub run_speed: f64,
+  // Rates (units/s per second) at which horizontal speed approaches the run speed,
+  // or zero when there's no input
+  pub ground_acceleration: f64,
+  pub ground_deceleration: f64,
+  pub air_acceleration: f64,
+  pub air_deceleration: f64,
+  pub jump_speed: f64,
+  // Upward speed is scaled by this when jump is let go early, for short hops
+  pub jump_cut: f64,
+  // Seconds after leaving the ground that a jump still works
+  pub coyote_time: f64,
+  // Seconds a jump pressed in the air is remembered for the landing
+  pub jump_buffer: f64,
+  pub max_fall_speed: f64,
+  // Falling while pushing against a wall is slowed to wall_slide_speed
+  pub wall_slide: bool,
+  pub wall_slide_speed: f64,
+  // Jumping off a wall kicks away from it at wall_jump_kick
+  pub wall_jump: bool,
+  pub wall_jump_kick: f64,
+  pub wall_jump_speed: f64,
+  // Seconds after a wall jump that walking input is ignored, 0 for none
+  pub wall_jump_lockout: f64,
+  pub climb_speed: f64,
+  pub swim_speed: f64,
+  // Upward speed from each jump press while swimming
+  pub swim_stroke: f64,
+  #[serde(skip)]
+  pub state: ControllerState,
+}
+
+impl Default for Controller {
+  fn default() -> Controller {
+    Controller {
+      run_speed: 24.,
+      ground_acceleration: 400.,
+      ground_deceleration: 600.,
+      air_acceleration: 200.,
+      air_deceleration: 50.,
+      jump_speed: 100.,
+      jump_cut: 0.5,
+      coyote_time: 0.1,
+      jump_buffer: 0.1,
+      max_fall_speed: 200.,
+      wall_slide: true,
+      wall_slide_speed: 20.,
+      wall_jump: true,
+      wall_jump_kick: 40.,
+      wall_jump_speed: 100.,
+      wall_jump_lockout: 0.15,
+      climb_speed: 12.,
+      swim_speed: 16.,
+      swim_stroke: 40.,
+      state: ControllerState::default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerState {
+  pub since_grounded: f64,
+  // Time left on a buffered jump
+  pub buffered: Option<f64>,
+  // Rising from a jump that can still be cut short
+  pub jumping: bool,
+  // Time left ignoring walking input after a wall jump
+  pub lockout: f64,
+  // Holding on to a ladder
+  pub climbing: bool,
+  // Ground acceleration and deceleration are scaled by this, from the surface's friction
+  pub grip: f64,
+}
+
+impl Default for ControllerState {
+  fn default() -> ControllerState {
+    ControllerState {
+      since_grounded: f64::INFINITY,
+      buffered: None,
+      jumping: false,
+      lockout: 0.,
+      climbing: false,
+      grip: 1.,
+    }
+  }
+}
+
+// What the player is asking for this step
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ControlInput {
+  // -1 to 1
+  pub walk: f64,
+  pub jump_pressed: bool,
+  pub jump_held: bool,
+}
+
+impl Controller {
+  // Shell tuning, field names as in the struct. Switches are on for anything but 0.
+  pub fn set(&mut self, field: &str, value: f64) -> Result<(), String> {
+    match field {
+      "run_speed" => self.run_speed = value,
+      "ground_acceleration" => self.ground_acceleration = value,
+      "ground_deceleration" => self.ground_deceleration = value,
+      "air_acceleration" => self.air_acceleration = value,
+      "air_deceleration" => self.air_deceleration = value,
+      "jump_speed" => self.jump_speed = value,
+      "jump_cut" => self.jump_cut = value,
+      "coyote_time" => self.coyote_time = value,
+      "jump_buffer" => self.jump_buffer = value,
+      "max_fall_speed" => self.max_fall_speed = value,
+      "wall_slide" => self.wall_slide = value != 0.,
+      "wall_slide_speed" => self.wall_slide_speed = value,
+      "wall_jump" => self.wall_jump = value != 0.,
+      "wall_jump_kick" => self.wall_jump_kick = value,
+      "wall_jump_speed" => self.wall_jump_speed = value,
+      "wall_jump_lockout" => self.wall_jump_lockout = value,
+      "climb_speed" => self.climb_speed = value,
+      "swim_speed" => self.swim_speed = value,
+      "swim_stroke" => self.swim_stroke = value,
+      _ => return Err(format!("No controller field {}", field)),
+    }
+    Ok(())
+  }
+
+  // One fixed step, run before physics. The controller owns horizontal speed,
+  // so steered bodies don't get ground friction.
+  // `wall` is the side of a wall the body is pushing against, from the last physics step.
+  // Returns whether it steered, it doesn't for a while after a wall jump or a launch.
+  pub fn update(&mut self, input: &ControlInput, grounded: bool, wall: Option<f64>, vel: &mut Velocity, dt: f64) -> bool {
+    let on_wall = if grounded { None } else { wall };
+    if grounded {
+      self.state.since_grounded = 0.;
+    } else {
+      self.state.since_grounded += dt;
+    }
+
+    self.state.buffered = match self.state.buffered {
+      Some(t) if t > dt => Some(t - dt),
+      _ => None,
+    };
+    if input.jump_pressed {
+      self.state.buffered = Some(self.jump_buffer);
+    }
+    if self.state.buffered.is_some() && self.state.since_grounded <= self.coyote_time {
+      vel.y = self.jump_speed;
+      self.state.buffered = None;
+      self.state.jumping = true;
+      // No second jump off the same coyote time
+      self.state.since_grounded = f64::INFINITY;
+    } else if let (Some(_), Some(side), true) = (self.state.buffered, on_wall, self.wall_jump) {
+      vel.x = -side * self.wall_jump_kick;
+      vel.y = self.wall_jump_speed;
+      self.state.buffered = None;
+      self.state.jumping = true;
+      self.state.lockout = self.wall_jump_lockout;
+    }
+    if self.state.jumping {
+      if vel.y <= 0. {
+        self.state.jumping = false;
+      } else if !input.jump_held {
+        vel.y *= self.jump_cut;
+        self.state.jumping = false;
+      }
+    }
+
+    let steered = self.state.lockout <= 0.;
+    if steered {
+      let speed = self.run_speed;
+      self.walk(input, grounded, speed, vel, dt);
+    } else {
+      self.state.lockout -= dt;
+    }
+
+    vel.y = vel.y.max(-self.max_fall_speed);
+    if self.wall_slide && on_wall.is_some() {
+      vel.y = vel.y.max(-self.wall_slide_speed);
+    }
+    steered
+  }
+
+  // Thrown by a launcher, no steering and no cutting the launch short for a while
+  pub fn launched(&mut self, lockout: f64) {
+    self.state.lockout = self.state.lockout.max(lockout);
+    self.state.jumping = false;
+  }
+
+  // Up or down (`aim`) on a ladder grabs it, then the body moves at climb speed with no
+  // gravity. Letting go on the ground, leaving the ladder or jumping lets go of it.
+  // Returns whether `update` should sit out this step.
+  pub fn climb(&mut self, input: &ControlInput, aim: f64, ladder: bool, grounded: bool, vel: &mut Velocity) -> bool {
+    if !ladder || (grounded && aim == 0.) {
+      self.state.climbing = false;
+    } else if aim != 0. {
+      self.state.climbing = true;
+    }
+    if !self.state.climbing {
+      return false;
+    }
+    if input.jump_pressed {
+      vel.x = input.walk * self.run_speed;
+      vel.y = self.jump_speed;
+      self.state.climbing = false;
+      self.state.jumping = true;
+      self.state.buffered = None;
+      self.state.since_grounded = f64::INFINITY;
+      return true;
+    }
+    *vel = Vec2::new(input.walk, aim) * self.climb_speed;
+    true
+  }
+
+  // In deep enough water every jump press is a stroke upward, and walking is swimming.
+  // The liquid itself takes care of drag and gravity. Returns whether `update` should sit out.
+  pub fn swim(&mut self, input: &ControlInput, swimming: bool, vel: &mut Velocity, dt: f64) -> bool {
+    if !swimming {
+      return false;
+    }
+    if input.jump_pressed {
+      vel.y = vel.y.max(self.swim_stroke);
+    }
+    self.state.buffered = None;
+    self.state.jumping = false;
+    // No coyote jumps on the way out
+    self.state.since_grounded = f64::INFINITY;
+    let speed = self.swim_speed;
+    self.walk(input, false, speed, vel, dt);
+    true
+  }
+
+  fn walk(&self, input: &ControlInput, grounded: bool, speed: f64, vel: &mut Velocity, dt: f64) {
+    let target = input.walk * speed;
+    let rate = match (grounded, input.walk != 0.) {
+      (true, true) => self.ground_acceleration,
+      (true, false) => self.ground_deceleration,
+      (false, true) => self.air_acceleration,
+      (false, false) => self.air_deceleration,
+    };
+    let change = rate * dt * if grounded { self.state.grip } else { 1. };
+    vel.x = if (target - vel.x).abs() <= change {
+      target
+    } else {
+      vel.x + change * (target - vel.x).signum()
+    };
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate serde_json;
+  use super::*;
+  use components::World;
+  use prefab::Prefab;
+
+  #[test]
+  fn forgives_late_and_early_jumps() {
+    let dt = 0.01;
+    let press = ControlInput { jump_pressed: true, jump_held: true, ..ControlInput::default() };
+    let hold = ControlInput { jump_held: true, ..ControlInput::default() };
+
+    // Walked off a ledge a few steps ago
+    let mut c = Controller::default();
+    let mut vel = Vec2::new(0., 0.);
+    c.update(&hold, true, None, &mut vel, dt);
+    for _ in 0..5 {
+      c.update(&hold, false, None, &mut vel, dt);
+    }
+    c.update(&press, false, None, &mut vel, dt);
+    assert_eq!(vel.y, c.jump_speed);
+    // but only once
+    vel.y = -10.;
+    c.update(&press, false, None, &mut vel, dt);
+    assert_eq!(vel.y, -10.);
+
+    // Pressed just before landing, jumps on the landing step
+    for _ in 0..5 {
+      c.update(&hold, false, None, &mut vel, dt);
+    }
+    c.update(&hold, true, None, &mut vel, dt);
+    assert_eq!(vel.y, c.jump_speed);
+
+    // Letting go early cuts the jump
+    c.update(&ControlInput::default(), false, None, &mut vel, dt);
+    assert_eq!(vel.y, c.jump_speed * c.jump_cut);
+
+    // Speeds up and falls no faster than allowed
+    let mut vel = Vec2::new(0., -1000.);
+    c.update(&ControlInput { walk: 1., ..ControlInput::default() }, true, None, &mut vel, dt);
+    assert_eq!(vel, Vec2::new(c.ground_acceleration * dt, -c.max_fall_speed));
+  }
+
+  #[test]
+  fn slides_down_and_jumps_off_walls() {
+    let dt = 0.01;
+    let push = ControlInput { walk: 1., jump_held: true, ..ControlInput::default() };
+    let mut c = Controller::default();
+    let mut vel = Vec2::new(0., -100.);
+    c.update(&push, false, Some(1.), &mut vel, dt);
+    assert_eq!(vel.y, -c.wall_slide_speed);
+
+    // Kicked away from the wall on the right, pushing right does nothing for a moment
+    assert!(!c.update(&ControlInput { jump_pressed: true, ..push }, false, Some(1.), &mut vel, dt));
+    assert_eq!(vel, Vec2::new(-c.wall_jump_kick, c.wall_jump_speed));
+    assert!(!c.update(&push, false, None, &mut vel, dt));
+    assert_eq!(vel.x, -c.wall_jump_kick);
+
+    c.wall_slide = false;
+    c.wall_jump = false;
+    let mut vel = Vec2::new(0., -100.);
+    c.update(&ControlInput { jump_pressed: true, ..push }, false, Some(1.), &mut vel, dt);
+    assert_eq!(vel.y, -100.);
+  }
+
+  #[test]
+  fn tuning_is_kept_through_a_reload() {
+    let mut w = World::new();
+    let id = w.instantiate_prefab("player", Prefab::default()).unwrap();
+    let mut controller = w.controllers.get(&id).cloned().unwrap_or_default();
+    controller.set("jump_speed", 150.).unwrap();
+    w.controllers.insert(id, controller.clone());
+    w.override_instance(id, |overrides| overrides.controller = Some(controller));
+
+    let mut loaded: World = serde_json::from_str(&w.to_json()).unwrap();
+    loaded.refresh_prefab_instances();
+    assert_eq!(loaded.controllers[&id].jump_speed, 150.);
+  }
+}
@@ -5,39 +5,77 @@ use self::sdl2::render::Renderer;
 use self::sdl2::pixels::Color;
 use self::sdl2::ttf::Font;
 use sdl2::render::TextureQuery;
+use sdl2::rect::Rect;
 
-use components::{World};
+use components::{World, ID};
 use common::{InputState, AABB, Vec2};
 use camera::Camera;
-use render::{draw_rect};
+use render::{draw_rect, draw_line};
+use prefab::{Prefab, list_prefabs};
 
 
+// What drawn boxes become, T cycles through them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrawKind {
+  Obstacle,
+  Sensor,
+  Water,
+}
+
 pub struct DrawObstacleTool {
   pub pos: Vec2,
   pub start_pos: Option<Vec2>,
+  pub kind: DrawKind,
 }
 impl DrawObstacleTool {
   pub fn new() -> DrawObstacleTool {
     DrawObstacleTool {
       pos: Vec2::new(0., 0.),
       start_pos: None,
+      kind: DrawKind::Obstacle,
+    }
+  }
+}
+
+pub struct PrefabPaletteTool {
+  pub prefabs: Vec<String>,
+  pub selected: usize,
+}
+impl PrefabPaletteTool {
+  pub fn new() -> PrefabPaletteTool {
+    PrefabPaletteTool {
+      prefabs: list_prefabs(),
+      selected: 0,
     }
   }
+  pub fn current(&self) -> Option<&String> {
+    self.prefabs.get(self.selected)
+  }
 }
 
 pub struct Editor {
   obstacle_tool: DrawObstacleTool,
+  palette: PrefabPaletteTool,
 }
 impl Editor {
   pub fn new() -> Editor {
     Editor {
       obstacle_tool: DrawObstacleTool::new(),
+      palette: PrefabPaletteTool::new(),
     }
   }
 }
 
 fn obstacle_tool_input(input: &InputState, tool: &mut DrawObstacleTool, camera: &Camera, create: &mut Vec<AABB>) {
   let min_axis = 0.5;
+  if input.key_pressed(&Keycode::T) {
+    tool.kind = match tool.kind {
+      DrawKind::Obstacle => DrawKind::Sensor,
+      DrawKind::Sensor => DrawKind::Water,
+      DrawKind::Water => DrawKind::Obstacle,
+    };
+    println!("drawing {:?}", tool.kind);
+  }
   tool.pos = camera.screen2world(input.mouse.x(), input.mouse.y());
   if let Some(start_pos) = tool.start_pos {
     if !input.mouse_down(MouseButton::Left) {
@@ -60,12 +98,31 @@ fn obstacle_tool_input(input: &InputState, tool: &mut DrawObstacleTool, camera:
   }
 }
 
+fn palette_input(input: &InputState, palette: &mut PrefabPaletteTool, camera: &Camera, spawn: &mut Vec<(String, Vec2)>) {
+  if palette.prefabs.is_empty() {
+    return;
+  }
+  if input.key_pressed(&Keycode::Tab) {
+    palette.selected = (palette.selected + 1) % palette.prefabs.len();
+    println!("palette: {}", palette.prefabs[palette.selected]);
+  }
+  if input.mouse_pressed(MouseButton::Right) {
+    if let Some(name) = palette.current() {
+      spawn.push((name.clone(), camera.screen2world(input.mouse.x(), input.mouse.y())));
+    }
+  }
+}
+
 fn render_obstacle_tool(tool: &DrawObstacleTool, camera: &Camera, renderer: &mut Renderer) {
   if let Some(start_pos) = tool.start_pos {
     draw_rect(renderer, camera,
       Vec2::new(start_pos.x.min(tool.pos.x), start_pos.y.min(tool.pos.y)),
       (start_pos - tool.pos).abs(),
-      Color::RGBA(90, 150, 20, 120),
+      match tool.kind {
+        DrawKind::Obstacle => Color::RGBA(90, 150, 20, 120),
+        DrawKind::Sensor => Color::RGBA(0, 160, 80, 80),
+        DrawKind::Water => Color::RGBA(40, 90, 255, 100),
+      },
     );
   }
 
@@ -77,17 +134,54 @@ fn render_editor(world: &Editor, renderer: &mut Renderer, camera: &Camera) {
   render_obstacle_tool(&world.obstacle_tool, camera, renderer);
 }
 
+// Middle of the collider, or just the position for entities without one
+fn wire_end(world: &World, id: ID) -> Option<Vec2> {
+  match world.get_collider_entity(id) {
+    Some((p, c)) => Some(c.offset(*p).center),
+    None => world.positions.get(&id).cloned(),
+  }
+}
+
+// Lit while the signal going down the wire is on
+fn render_wires(world: &World, renderer: &mut Renderer, camera: &Camera) {
+  for (from, to) in world.wires() {
+    if let (Some(a), Some(b)) = (wire_end(world, from), wire_end(world, to)) {
+      let color = if world.signal(from) { Color::RGBA(255, 230, 0, 255) } else { Color::RGBA(120, 120, 120, 255) };
+      draw_line(renderer, camera, a, b, color);
+    }
+  }
+}
+
+fn render_palette(palette: &PrefabPaletteTool, renderer: &mut Renderer, font: &mut Font) {
+  if let Some(name) = palette.current() {
+    let surface = font.render(name)
+      .blended(Color::RGBA(255, 255, 255, 255)).unwrap();
+    let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
+    let TextureQuery { width, height, .. } = texture.query();
+    let target = Rect::new(4, 4, width / 2, height / 2);
+    renderer.copy(&mut texture, None, Some(target)).unwrap();
+  }
+}
+
 pub fn run_editor_systems(world: &mut World, editor: &mut Editor, input: &InputState, renderer: &mut Renderer, font: &mut Font) {
   let mut create_statics = Vec::new();
+  let mut spawn_prefabs = Vec::new();
   if let Some(camera) = world.cameras.get(&world.current_camera) {
     // Systems that need the camera (screen-space tools)
     obstacle_tool_input(input, &mut editor.obstacle_tool, camera, &mut create_statics);
+    palette_input(input, &mut editor.palette, camera, &mut spawn_prefabs);
     render_editor(editor, renderer, camera);
+    render_wires(world, renderer, camera);
+    render_palette(&editor.palette, renderer, font);
 
     // Draw ID on each entity
     for id in &world.entities {
       if let Some((p, c)) = world.get_collider_entity(*id) {
-        let surface = font.render(&format!("{}", id))
+        let label = match world.names.get(id) {
+          Some(name) => format!("{} {}", id, name),
+          None => format!("{}", id),
+        };
+        let surface = font.render(&label)
           .blended(Color::RGBA(0, 0, 0, 255)).unwrap();
         let mut texture = renderer.create_texture_from_surface(&surface).unwrap();
         let TextureQuery { width, height, .. } = texture.query();
@@ -102,6 +196,16 @@ pub fn run_editor_systems(world: &mut World, editor: &mut Editor, input: &InputS
     }
   }
   for bbox in &create_statics {
-    world.new_static_obstacle(bbox.center, bbox.half_size * 2.);
+    match editor.obstacle_tool.kind {
+      DrawKind::Obstacle => world.new_static_obstacle(bbox.center, bbox.half_size * 2.),
+      DrawKind::Sensor => world.new_sensor(bbox.center, bbox.half_size * 2.),
+      DrawKind::Water => world.new_water(bbox.center, bbox.half_size * 2.),
+    };
+  }
+  for (name, pos) in spawn_prefabs {
+    match world.instantiate_prefab(&name, Prefab::at(pos)) {
+      Ok(id) => println!("spawned {} as {}", name, id),
+      Err(e) => println!("couldn't spawn {}: {}", name, e),
+    }
   }
 }
@@ -0,0 +1,68 @@
+use std::mem;
+
+use common::Vec2;
+use components::ID;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+  // `id` is the moving body, `other` whatever it touched
+  CollisionBegin { id: ID, other: ID },
+  CollisionEnd { id: ID, other: ID },
+  Landed { id: ID, on: ID },
+  BonkedHead { id: ID, on: ID },
+  // Published for every sensor/body pair each fixed step they overlap
+  TriggerEnter { sensor: ID, id: ID },
+  TriggerStay { sensor: ID, id: ID },
+  TriggerExit { sensor: ID, id: ID },
+  // A body going into (or out of) a liquid, `at` is where the body was
+  Splash { id: ID, liquid: ID, at: Vec2, entering: bool },
+  // A spring or bumper threw a body
+  Launched { id: ID, by: ID },
+  // Sent back by a hazard or the kill plane
+  Respawned { id: ID, at: Vec2 },
+  // Had nowhere to respawn, so it was deleted
+  Removed { id: ID },
+}
+
+// Typed queue that lets systems talk to each other. The world loop calls `end_frame`
+// once per rendered frame, after everything has run, not once per fixed step.
+// Systems in LateUpdate and Render read `this_frame` and see every event of the frame's
+// fixed steps exactly once, Input systems read `last_frame` for the same.
+// FixedUpdate systems can't rely on either: a frame with several steps shows later steps
+// the earlier steps' events again, so fixed step code should read world state instead.
+#[derive(Debug)]
+pub struct EventQueue {
+  current: Vec<Event>,
+  previous: Vec<Event>,
+}
+
+impl EventQueue {
+  pub fn new() -> EventQueue {
+    EventQueue {
+      current: Vec::new(),
+      previous: Vec::new(),
+    }
+  }
+
+  pub fn publish(&mut self, event: Event) {
+    self.current.push(event);
+  }
+
+  pub fn this_frame(&self) -> &[Event] {
+    &self.current
+  }
+
+  pub fn last_frame(&self) -> &[Event] {
+    &self.previous
+  }
+
+  pub fn end_frame(&mut self) {
+    self.previous = mem::replace(&mut self.current, Vec::new());
+  }
+}
+
+impl Default for EventQueue {
+  fn default() -> EventQueue {
+    EventQueue::new()
+  }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+
+use common::Vec2;
+use components::{World, ID};
+
+// Guards against cycles in badly authored levels
+const MAX_DEPTH: usize = 32;
+
+// Attaches an entity to another one. The child's position is no longer simulated,
+// it is recomputed every frame from the parent's position plus the local offset.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Parent {
+  pub id: ID,
+  pub offset: Vec2,
+}
+
+// An entity's own position, cameras keep theirs on the Camera
+fn own_position(w: &World, id: ID) -> Option<Vec2> {
+  match w.positions.get(&id) {
+    Some(p) => Some(*p),
+    None => w.cameras.get(&id).map(|c| c.pos),
+  }
+}
+
+fn world_position(w: &World, id: ID, depth: usize) -> Option<Vec2> {
+  match w.parents.get(&id) {
+    Some(parent) if depth < MAX_DEPTH => {
+      world_position(w, parent.id, depth + 1).map(|p| p + parent.offset)
+    },
+    _ => own_position(w, id),
+  }
+}
+
+pub fn is_ancestor(w: &World, ancestor: ID, id: ID) -> bool {
+  let mut current = id;
+  for _ in 0..MAX_DEPTH {
+    match w.parents.get(&current) {
+      Some(parent) if parent.id == ancestor => return true,
+      Some(parent) => current = parent.id,
+      None => return false,
+    }
+  }
+  false
+}
+
+pub fn children_of(w: &World, id: ID) -> Vec<ID> {
+  w.parents.iter()
+    .filter(|&(_, parent)| parent.id == id)
+    .map(|(child, _)| *child)
+    .collect()
+}
+
+// Compute world positions for every child from its chain of parents.
+// Runs before physics and before render so both see up to date transforms.
+pub fn propagate_transforms(w: &mut World) {
+  let mut updates: HashMap<ID, Vec2> = HashMap::new();
+  for id in w.parents.keys() {
+    if let Some(pos) = world_position(w, *id, 0) {
+      updates.insert(*id, pos);
+    }
+  }
+  for (id, pos) in updates {
+    if let Some(ref mut camera) = w.cameras.get_mut(&id) {
+      camera.pos = pos;
+    }
+    if w.positions.contains_key(&id) {
+      w.positions.insert(id, pos);
+    }
+  }
+}
+
+impl World {
+  // Attach child to parent, keeping the child where it currently is
+  pub fn attach(&mut self, child: ID, parent: ID) {
+    if !self.entities.contains(&child) || !self.entities.contains(&parent) {
+      return;
+    }
+    if child == parent || is_ancestor(self, child, parent) {
+      println!("Can't attach {} to {}, it would make a cycle", child, parent);
+      return;
+    }
+    let offset = match (world_position(self, child, 0), world_position(self, parent, 0)) {
+      (Some(c), Some(p)) => c - p,
+      _ => Vec2::new(0., 0.),
+    };
+    self.parents.insert(child, Parent { id: parent, offset: offset });
+  }
+
+  // Detach child from its parent, leaving it at its current world position
+  pub fn detach(&mut self, child: ID) {
+    self.parents.remove(&child);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chain_follows_root() {
+    let mut w = World::new();
+    let root = w.new_entity();
+    let mid = w.new_entity();
+    let leaf = w.new_entity();
+    w.positions.insert(root, Vec2::new(1., 1.));
+    w.positions.insert(mid, Vec2::new(0., 0.));
+    w.positions.insert(leaf, Vec2::new(0., 0.));
+    w.parents.insert(mid, Parent { id: root, offset: Vec2::new(2., 0.) });
+    w.parents.insert(leaf, Parent { id: mid, offset: Vec2::new(0., 3.) });
+
+    propagate_transforms(&mut w);
+    assert_eq!(w.positions[&leaf], Vec2::new(3., 4.));
+
+    w.positions.insert(root, Vec2::new(10., 0.));
+    propagate_transforms(&mut w);
+    assert_eq!(w.positions[&mid], Vec2::new(12., 0.));
+    assert_eq!(w.positions[&leaf], Vec2::new(12., 3.));
+
+    // Deleting a parent leaves its children in place
+    w.delete_entity(root);
+    assert!(w.entities.contains(&mid) && w.entities.contains(&leaf));
+    assert!(!w.parents.contains_key(&mid));
+    w.positions.insert(mid, Vec2::new(0., 0.));
+    propagate_transforms(&mut w);
+    assert_eq!(w.positions[&leaf], Vec2::new(0., 3.));
+  }
+}
@@ -0,0 +1,315 @@
+extern crate nalgebra;
+use self::nalgebra::{dot, norm};
+
+use std::collections::HashMap;
+
+use common::Vec2;
+use components::{World, ID};
+use query::{QueryFilter, sweep_aabb};
+use schedule::{Schedule, Stage};
+
+// Gap left between a body the solver moves and whatever stops it
+const SKIN: f64 = 0.01;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JointKind {
+  // No further apart than the length, slack inside it
+  Rope,
+  // Exactly the length apart, for hinged chains and bridges
+  Rod,
+  // Pulled toward the length, stiffness per unit stretched and damping per unit/s apart
+  Spring { stiffness: f64, damping: f64 },
+}
+
+fn centered() -> Vec2 {
+  Vec2::new(0., 0.)
+}
+
+// Ties two entities together. Entities without a velocity stay put, so joining a body
+// to an obstacle pins it there. Joints are entities of their own, deleting one cuts it,
+// and deleting either end deletes it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Joint {
+  pub a: ID,
+  pub b: ID,
+  // Where the joint holds on to each entity, from its position
+  #[serde(default = "centered")]
+  pub anchor_a: Vec2,
+  #[serde(default = "centered")]
+  pub anchor_b: Vec2,
+  pub length: f64,
+  pub kind: JointKind,
+}
+
+impl Joint {
+  // Both ends, while both entities are around
+  pub fn ends(&self, w: &World) -> Option<(Vec2, Vec2)> {
+    match (w.positions.get(&self.a), w.positions.get(&self.b)) {
+      (Some(a), Some(b)) => Some((*a + self.anchor_a, *b + self.anchor_b)),
+      _ => None,
+    }
+  }
+}
+
+impl World {
+  // Joins the two at their positions, as far apart as they are now
+  pub fn new_joint(&mut self, a: ID, b: ID, kind: JointKind) -> ID {
+    let length = match (self.positions.get(&a), self.positions.get(&b)) {
+      (Some(pa), Some(pb)) => norm(&(*pb - *pa)),
+      _ => 0.,
+    };
+    let id = self.new_entity();
+    self.joints.insert(id, Joint {
+      a: a,
+      b: b,
+      anchor_a: centered(),
+      anchor_b: centered(),
+      length: length,
+      kind: kind,
+    });
+    id
+  }
+}
+
+// Share of a correction an entity takes, by mass. Static and attached ones take none.
+fn inverse_mass(w: &World, id: ID) -> f64 {
+  if !w.velocities.contains_key(&id) || w.parents.contains_key(&id) {
+    0.
+  } else {
+    1. / *w.masses.get(&id).unwrap_or(&1.)
+  }
+}
+
+// Moves a body by `d`, stopping at level geometry and sliding along it for the rest.
+// Other bodies don't stop it, physics pushes them out on the next step.
+fn nudge(w: &mut World, id: ID, d: Vec2) {
+  let mut pos = match w.positions.get(&id) {
+    Some(pos) => *pos,
+    None => return,
+  };
+  let coll = match w.collisions.get(&id) {
+    Some(c) if !c.sensor => c.clone(),
+    _ => {
+      w.positions.insert(id, pos + d);
+      return;
+    },
+  };
+  let filter = QueryFilter { layer: coll.layer, mask: coll.mask, exclude: Some(id), statics_only: true, ..QueryFilter::default() };
+  let mut rest = d;
+  for _ in 0..2 {
+    let length = norm(&rest);
+    if length == 0. {
+      break;
+    }
+    match sweep_aabb(w, &coll.offset(pos), rest, &filter) {
+      Some(hit) => {
+        pos += rest * ((hit.distance - SKIN).max(0.) / length);
+        let left = rest * (1. - hit.distance.max(0.) / length);
+        rest = left - hit.normal * dot(&left, &hit.normal);
+      },
+      None => {
+        pos += rest;
+        break;
+      },
+    }
+  }
+  w.positions.insert(id, pos);
+}
+
+// Bodies standing on top of `id`, from what touched it this step
+fn riders(w: &World, id: ID) -> Vec<ID> {
+  let top = match w.get_collider_entity(id) {
+    Some((pos, coll)) => coll.offset(*pos).top_right().y,
+    None => return Vec::new(),
+  };
+  let mut riders: Vec<ID> = w.contacts.iter()
+    .filter_map(|&(a, b)| if a == id { Some(b) } else if b == id { Some(a) } else { None })
+    .filter(|other| w.velocities.contains_key(other) && !w.joints.values().any(|j| j.a == *other || j.b == *other))
+    .filter(|other| w.get_collider_entity(*other).map_or(false, |(pos, coll)| coll.offset(*pos).bottom_left().y >= top - SKIN))
+    .collect();
+  riders.sort();
+  riders.dedup();
+  riders
+}
+
+fn velocity(w: &World, id: ID) -> Vec2 {
+  w.velocities.get(&id).cloned().unwrap_or(Vec2::new(0., 0.))
+}
+
+fn add_velocity(w: &mut World, id: ID, dv: Vec2) {
+  if let Some(v) = w.velocities.get_mut(&id) {
+    *v += dv;
+  }
+}
+
+// Springs push and pull once per step. Ropes and rods are solved a few times over,
+// each pass moving both ends back to the length and taking out the speed along it,
+// so chains settle in a step instead of stretching.
+fn joint_system(w: &mut World, dt: f64) {
+  let mut ids: Vec<ID> = w.joints.keys().cloned().collect();
+  ids.sort();
+  let mut start: HashMap<ID, Vec2> = HashMap::new();
+  for id in &ids {
+    let joint = w.joints[id];
+    for end in &[joint.a, joint.b] {
+      if let Some(pos) = w.positions.get(end) {
+        start.insert(*end, *pos);
+      }
+    }
+  }
+  let mut carried: Vec<(ID, Vec<ID>)> = start.keys()
+    .filter(|id| w.velocities.contains_key(id))
+    .map(|id| (*id, riders(w, *id)))
+    .filter(|&(_, ref riders)| !riders.is_empty())
+    .collect();
+  carried.sort();
+
+  for pass in 0..w.physics.joint_iterations.max(1) {
+    for id in &ids {
+      let joint = w.joints[id];
+      let (ia, ib) = (inverse_mass(w, joint.a), inverse_mass(w, joint.b));
+      let (pa, pb) = match joint.ends(w) {
+        Some(ends) => ends,
+        None => continue,
+      };
+      let dist = norm(&(pb - pa));
+      if ia + ib == 0. || dist == 0. {
+        continue;
+      }
+      // From a toward b
+      let n = (pb - pa) / dist;
+      let stretch = dist - joint.length;
+      let apart = dot(&(velocity(w, joint.b) - velocity(w, joint.a)), &n);
+      match joint.kind {
+        JointKind::Spring { stiffness, damping } => {
+          if pass == 0 {
+            let force = stiffness * stretch + damping * apart;
+            add_velocity(w, joint.a, n * (force * ia * dt));
+            add_velocity(w, joint.b, -n * (force * ib * dt));
+          }
+        },
+        JointKind::Rope if stretch <= 0. => {},
+        kind => {
+          let share = stretch / (ia + ib);
+          if ia > 0. {
+            nudge(w, joint.a, n * (share * ia));
+          }
+          if ib > 0. {
+            nudge(w, joint.b, -n * (share * ib));
+          }
+          // Ropes only stop the ends moving apart
+          if kind == JointKind::Rod || apart > 0. {
+            let share = apart / (ia + ib);
+            add_velocity(w, joint.a, n * (share * ia));
+            add_velocity(w, joint.b, -n * (share * ib));
+          }
+        },
+      }
+    }
+  }
+
+  // Whatever stands on a jointed body goes where it went, and stands on it
+  // so it can walk and jump off
+  for (id, riders) in carried {
+    let d = w.positions[&id] - start[&id];
+    for rider in riders {
+      nudge(w, rider, d);
+      w.groundables.insert(rider, true);
+      w.supports.insert(rider, id);
+      if let Some(v) = w.velocities.get_mut(&rider) {
+        v.y = v.y.max(0.);
+      }
+    }
+  }
+}
+
+pub fn register_systems(schedule: &mut Schedule) {
+  schedule.add_world(Stage::FixedUpdate, "joints", joint_system).after("mover_blocks");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time;
+  use components::Collision;
+  use physics;
+
+  #[test]
+  fn ropes_swing_chains_hang_and_springs_settle() {
+    let mut w = World::new();
+    let mut schedule = Schedule::new();
+    physics::register_systems(&mut schedule);
+    register_systems(&mut schedule);
+    let anchor = w.new_static_obstacle(Vec2::new(0., 20.), Vec2::new(1., 1.));
+    // Rope starting out level, a two link chain, and a spring
+    let bob = w.test_body(Vec2::new(10., 20.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    w.new_joint(anchor, bob, JointKind::Rope);
+    let link = w.test_body(Vec2::new(30., 20.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    let end = w.test_body(Vec2::new(35., 20.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    let hook = w.new_static_obstacle(Vec2::new(30., 25.), Vec2::new(1., 1.));
+    w.new_joint(hook, link, JointKind::Rod);
+    w.new_joint(link, end, JointKind::Rod);
+    let weight = w.test_body(Vec2::new(-20., 10.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    let ceiling = w.new_static_obstacle(Vec2::new(-20., 20.), Vec2::new(1., 1.));
+    w.new_joint(ceiling, weight, JointKind::Spring { stiffness: 1000., damping: 40. });
+
+    let mut lowest: f64 = 20.;
+    let mut swung_past = false;
+    for _ in 0..1000 {
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+      let pos = w.positions[&bob];
+      assert!(norm(&(pos - Vec2::new(0., 20.))) <= 10. + 1e-6);
+      lowest = lowest.min(pos.y);
+      swung_past |= pos.x < -5.;
+      let (l, e) = (w.positions[&link], w.positions[&end]);
+      assert!((norm(&(l - Vec2::new(30., 25.))) - 5.).abs() < 0.01);
+      assert!((norm(&(e - l)) - 5.).abs() < 0.01);
+    }
+    // Swung down through the bottom and up the other side, and the chain ends up hanging straight
+    assert!(lowest < 10.1 && swung_past);
+    assert!((w.positions[&end] - Vec2::new(30., 15.)).norm() < 0.5, "{:?}", w.positions[&end]);
+    // Stretched by its weight, 500 gravity over 1000 stiffness
+    assert!((w.positions[&weight].y - 9.5).abs() < 0.05, "{:?}", w.positions[&weight]);
+  }
+
+  #[test]
+  fn riders_go_with_a_swinging_plank() {
+    let mut w = World::new();
+    let mut schedule = Schedule::new();
+    physics::register_systems(&mut schedule);
+    register_systems(&mut schedule);
+    let beam = w.new_static_obstacle(Vec2::new(0., 20.), Vec2::new(10., 1.));
+    let plank = w.test_body(Vec2::new(0., 10.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    w.collisions.insert(plank, Collision::new(Vec2::new(0., 0.), Vec2::new(3., 0.5)));
+    let left = w.new_joint(beam, plank, JointKind::Rope);
+    let right = w.new_joint(beam, plank, JointKind::Rope);
+    for &(joint, x) in &[(left, -3.), (right, 3.)] {
+      let j = w.joints.get_mut(&joint).unwrap();
+      j.anchor_a = Vec2::new(x, -1.);
+      j.anchor_b = Vec2::new(x, 0.5);
+      j.length = 9.;
+    }
+    let rider = w.test_body(Vec2::new(0., 11.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+    for _ in 0..50 {
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+    }
+    assert_eq!(w.supports.get(&rider), Some(&plank));
+
+    w.velocities.insert(plank, Vec2::new(30., 0.));
+    let mut furthest: f64 = 0.;
+    for _ in 0..30 {
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+      let (p, r) = (w.positions[&plank], w.positions[&rider]);
+      assert!((r.y - p.y - 1.).abs() < 0.05 && (r.x - p.x).abs() < 0.5, "{:?} {:?}", p, r);
+      furthest = furthest.max(p.x);
+    }
+    assert!(furthest > 2.);
+    assert_eq!(w.supports.get(&rider), Some(&plank));
+
+    // Cutting the plank down takes its ropes with it
+    w.delete_entity(plank);
+    assert!(w.joints.is_empty());
+    assert!(!w.entities.contains(&left) && !w.entities.contains(&right));
+    assert!(w.entities.contains(&beam));
+  }
+}
@@ -0,0 +1,83 @@
+// Colliders sit on layers and only collide with the layers in their mask,
+// two colliders interact when each one's layer is in the other's mask.
+pub type LayerBits = u32;
+
+pub const WORLD: LayerBits = 1;
+pub const ALL_LAYERS: LayerBits = !0;
+
+pub fn world_layer() -> LayerBits {
+  WORLD
+}
+
+pub fn all_layers() -> LayerBits {
+  ALL_LAYERS
+}
+
+pub fn interacts(layer_a: LayerBits, mask_a: LayerBits, layer_b: LayerBits, mask_b: LayerBits) -> bool {
+  layer_a & mask_b != 0 && layer_b & mask_a != 0
+}
+
+// Names for the layer bits, stored with the level. Layer i is bit 1 << i.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayerTable {
+  pub names: Vec<String>,
+}
+
+impl LayerTable {
+  pub fn bit(&self, name: &str) -> Option<LayerBits> {
+    self.names.iter()
+      .position(|n| n == name)
+      .map(|i| 1 << i)
+  }
+
+  // "all" and "none" work as masks too
+  pub fn bits(&self, names: &[String]) -> Result<LayerBits, String> {
+    let mut bits = 0;
+    for name in names {
+      bits |= match name.as_str() {
+        "all" => ALL_LAYERS,
+        "none" => 0,
+        _ => self.bit(name).ok_or(format!("No layer {}", name))?,
+      };
+    }
+    Ok(bits)
+  }
+
+  // Layers can be added per level, up to the width of the bits
+  pub fn add(&mut self, name: &str) -> Result<LayerBits, String> {
+    if let Some(bit) = self.bit(name) {
+      return Ok(bit);
+    }
+    if self.names.len() >= 32 {
+      return Err("Out of layers".to_owned());
+    }
+    self.names.push(name.to_owned());
+    Ok(1 << (self.names.len() - 1))
+  }
+}
+
+impl Default for LayerTable {
+  fn default() -> LayerTable {
+    LayerTable {
+      names: ["world", "player", "enemy", "pickup", "projectile"].iter().map(|n| n.to_string()).collect(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn names_map_to_bits() {
+    let mut table = LayerTable::default();
+    assert_eq!(table.bit("world"), Some(WORLD));
+    assert_eq!(table.bits(&["player".to_owned(), "pickup".to_owned()]), Ok(0b1010));
+    assert!(table.bits(&["nope".to_owned()]).is_err());
+    assert_eq!(table.add("water"), Ok(1 << 5));
+    assert_eq!(table.bits(&["world".to_owned(), "water".to_owned()]), Ok(0b100001));
+    // Pickups that only see the player
+    assert!(interacts(1 << 3, 1 << 1, 1 << 1, ALL_LAYERS));
+    assert!(!interacts(1 << 3, 1 << 1, WORLD, ALL_LAYERS));
+  }
+}
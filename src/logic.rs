@@ -0,0 +1,282 @@
+use std::collections::VecDeque;
+
+use components::{World, ID};
+use schedule::{Schedule, Stage};
+
+// A piece of level logic, on or off each step. Inputs are other logic entities,
+// wired by ID in the level file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Node {
+  // On while a body is in its sensor, for buttons and pressure plates
+  Plate,
+  // Flipped by the player interacting with its sensor
+  Lever,
+  And(Vec<ID>),
+  Or(Vec<ID>),
+  // Flips every time its input turns on
+  Toggle(ID),
+  // Follows its input, seconds later
+  Delay(ID, f64),
+  // On for seconds from when its input turns on, for timed gates
+  Timer(ID, f64),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Logic {
+  pub node: Node,
+  // Saved so levers and toggles keep their position with the level
+  #[serde(default)]
+  pub on: bool,
+  #[serde(skip)]
+  state: LogicState,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+struct LogicState {
+  // Input on the last step, to catch it turning on
+  input: bool,
+  // Time left on a timer
+  left: f64,
+  // Input changes on their way through a delay, with the time they have left
+  pending: VecDeque<(f64, bool)>,
+}
+
+// Solid while its input is off, opened by taking its collider away while on.
+// Inverted doors are open until their input turns on. Unwired doors stay shut.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Door {
+  #[serde(default)]
+  pub input: Option<ID>,
+  #[serde(default)]
+  pub inverted: bool,
+}
+
+impl Logic {
+  pub fn new(node: Node) -> Logic {
+    Logic {
+      node: node,
+      on: false,
+      state: LogicState::default(),
+    }
+  }
+
+  pub fn inputs(&self) -> Vec<ID> {
+    match self.node {
+      Node::Plate | Node::Lever => Vec::new(),
+      Node::And(ref inputs) | Node::Or(ref inputs) => inputs.clone(),
+      Node::Toggle(input) | Node::Delay(input, _) | Node::Timer(input, _) => vec![input],
+    }
+  }
+
+  // Nodes with memory move on once per step
+  fn step(&mut self, input: bool, dt: f64) {
+    let rising = input && !self.state.input;
+    match self.node {
+      Node::Toggle(_) => {
+        if rising {
+          self.on = !self.on;
+        }
+      },
+      Node::Delay(_, seconds) => {
+        for change in self.state.pending.iter_mut() {
+          change.0 -= dt;
+        }
+        if input != self.state.input {
+          self.state.pending.push_back((seconds, input));
+        }
+        while self.state.pending.front().map_or(false, |change| change.0 <= 0.) {
+          self.on = self.state.pending.pop_front().unwrap().1;
+        }
+      },
+      Node::Timer(_, seconds) => {
+        if rising {
+          self.state.left = seconds;
+        } else {
+          self.state.left -= dt;
+        }
+        self.on = self.state.left > 0.;
+      },
+      _ => {},
+    }
+    self.state.input = input;
+  }
+}
+
+impl World {
+  // Output of a logic entity, anything else is off
+  pub fn signal(&self, id: ID) -> bool {
+    self.logic.get(&id).map_or(false, |l| l.on)
+  }
+
+  pub fn door_open(&self, id: ID) -> bool {
+    self.doors.get(&id).map_or(false, |d| d.input.map_or(false, |input| self.signal(input) != d.inverted))
+  }
+
+  // Flips the levers the body is touching
+  pub fn interact(&mut self, id: ID) {
+    let mut levers: Vec<ID> = self.sensor_contacts.iter()
+      .filter(|&&(sensor, body)| body == id && self.logic.get(&sensor).map_or(false, |l| l.node == Node::Lever))
+      .map(|&(sensor, _)| sensor)
+      .collect();
+    levers.sort();
+    for lever in levers {
+      if let Some(l) = self.logic.get_mut(&lever) {
+        l.on = !l.on;
+      }
+    }
+  }
+
+  // (from, to) for every connection, for drawing the wiring
+  pub fn wires(&self) -> Vec<(ID, ID)> {
+    let mut wires = Vec::new();
+    for (id, l) in &self.logic {
+      for input in l.inputs() {
+        wires.push((input, *id));
+      }
+    }
+    for (id, door) in &self.doors {
+      if let Some(input) = door.input {
+        wires.push((input, *id));
+      }
+    }
+    for (id, block) in &self.mover_blocks {
+      if let Some(input) = block.input {
+        wires.push((input, *id));
+      }
+    }
+    wires
+  }
+}
+
+// Gates follow their inputs right away. Passes go until nothing changes,
+// or one per node so that loops can't hang the step.
+fn settle(w: &mut World, ids: &[ID]) {
+  for _ in 0..ids.len() {
+    let mut changed = false;
+    for id in ids {
+      let on = match w.logic[id].node {
+        Node::And(ref inputs) => inputs.iter().all(|i| w.signal(*i)),
+        Node::Or(ref inputs) => inputs.iter().any(|i| w.signal(*i)),
+        _ => continue,
+      };
+      let l = w.logic.get_mut(id).unwrap();
+      changed |= l.on != on;
+      l.on = on;
+    }
+    if !changed {
+      break;
+    }
+  }
+}
+
+// Runs after the sensors, so plates see this step's contacts
+fn logic_system(w: &mut World, dt: f64) {
+  let mut ids: Vec<ID> = w.logic.keys().cloned().collect();
+  ids.sort();
+  for id in &ids {
+    let pressed = w.sensor_contacts.iter().any(|&(sensor, _)| sensor == *id);
+    let l = w.logic.get_mut(id).unwrap();
+    if l.node == Node::Plate {
+      l.on = pressed;
+    }
+  }
+  settle(w, &ids);
+  for id in &ids {
+    let input = match w.logic[id].node {
+      Node::Toggle(input) | Node::Delay(input, _) | Node::Timer(input, _) => w.signal(input),
+      _ => continue,
+    };
+    w.logic.get_mut(id).unwrap().step(input, dt);
+  }
+  settle(w, &ids);
+}
+
+pub fn register_systems(schedule: &mut Schedule) {
+  schedule.add_world(Stage::FixedUpdate, "logic", logic_system).after("sensors");
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate serde_json;
+  use super::*;
+  use common::{AABB, Vec2};
+  use prefab::Prefab;
+  use query::{QueryFilter, overlap_aabb};
+
+  #[test]
+  fn switches_open_doors_through_gates() {
+    let dt = 0.125;
+    let mut w = World::new();
+    let plate = w.new_sensor(Vec2::new(0., 0.), Vec2::new(2., 2.));
+    let lever = w.new_sensor(Vec2::new(10., 0.), Vec2::new(2., 2.));
+    w.logic.insert(plate, Logic::new(Node::Plate));
+    w.logic.insert(lever, Logic::new(Node::Lever));
+    // Either switch opens the door for a second, a second later
+    let or = w.new_entity();
+    let delay = w.new_entity();
+    let timer = w.new_entity();
+    w.logic.insert(or, Logic::new(Node::Or(vec![plate, lever])));
+    w.logic.insert(delay, Logic::new(Node::Delay(or, 1.)));
+    w.logic.insert(timer, Logic::new(Node::Timer(delay, 1.)));
+    let door = w.new_static_obstacle(Vec2::new(20., 0.), Vec2::new(2., 4.));
+    w.doors.insert(door, Door { input: Some(timer), inverted: false });
+    let body = w.test_body(Vec2::new(10., 0.), Vec2::new(0., 0.), Vec2::new(0.5, 0.5));
+
+    let doorway = AABB::new(Vec2::new(20., 0.), Vec2::new(0.5, 0.5));
+    let blocked = |w: &World| overlap_aabb(w, &doorway, &QueryFilter::default()).contains(&door);
+    let mut open_steps = Vec::new();
+    for step in 0..40 {
+      if step == 0 {
+        w.sensor_contacts.insert((lever, body));
+        w.interact(body);
+      }
+      logic_system(&mut w, dt);
+      assert_eq!(w.door_open(door), !blocked(&w));
+      if w.door_open(door) {
+        open_steps.push(step);
+      }
+    }
+    assert!(w.signal(lever));
+    assert_eq!(open_steps, (8..16).collect::<Vec<_>>());
+    assert_eq!(w.wires().len(), 5);
+    let unwired = w.new_static_obstacle(Vec2::new(30., 0.), Vec2::new(2., 4.));
+    w.doors.insert(unwired, Door { input: None, inverted: true });
+    assert!(!w.door_open(unwired));
+    assert_eq!(w.wires().len(), 5);
+
+    // Toggles flip once per press, and movers only run while their input is on
+    let toggle = w.new_entity();
+    w.logic.insert(toggle, Logic::new(Node::Toggle(plate)));
+    let mover = w.new_mover_block(Vec2::new(0., 10.), Vec2::new(20., 10.), 4.);
+    w.mover_blocks.get_mut(&mover).unwrap().input = Some(toggle);
+    w.sensor_contacts.insert((plate, body));
+    logic_system(&mut w, dt);
+    logic_system(&mut w, dt);
+    assert!(w.signal(toggle));
+    w.sensor_contacts.remove(&(plate, body));
+    logic_system(&mut w, dt);
+    w.sensor_contacts.insert((plate, body));
+    logic_system(&mut w, dt);
+    assert!(!w.signal(toggle));
+    ::platforms::mover_blocks(&mut w, dt);
+    assert_eq!(w.positions[&mover], Vec2::new(0., 10.));
+  }
+
+  #[test]
+  fn wiring_survives_a_reload() {
+    let mut w = World::new();
+    let lever = w.instantiate_prefab("lever", Prefab::at(Vec2::new(0., 0.))).unwrap();
+    let door = w.instantiate_prefab("door", Prefab::at(Vec2::new(10., 0.))).unwrap();
+    let mover = w.instantiate_prefab("mover_block", Prefab::default()).unwrap();
+    w.doors.get_mut(&door).unwrap().input = Some(lever);
+    w.mover_blocks.get_mut(&mover).unwrap().input = Some(lever);
+
+    let mut loaded: World = serde_json::from_str(&w.to_json()).unwrap();
+    loaded.refresh_prefab_instances();
+    assert_eq!(loaded.doors[&door].input, Some(lever));
+    assert_eq!(loaded.mover_blocks[&mover].input, Some(lever));
+    assert!(!loaded.door_open(door));
+    loaded.logic.get_mut(&lever).unwrap().on = true;
+    assert!(loaded.door_open(door));
+  }
+}
@@ -9,6 +9,19 @@ mod systems;
 mod physics;
 mod editor;
 mod platforms;
+mod prefab;
+mod hierarchy;
+mod events;
+mod schedule;
+mod tilemap;
+mod triggers;
+mod layers;
+mod query;
+mod controller;
+mod abilities;
+mod water;
+mod logic;
+mod joints;
 
 use std::time;
 use std::path::Path;
@@ -24,9 +37,15 @@ use sdl2::keyboard::Keycode;
 use sdl2::pixels::Color;
 
 use common::{Vec2, InputState};
-use systems::{create_world, run_systems};
+use systems::{create_world, create_schedule};
 use editor::{Editor, run_editor_systems};
 use components::{World};
+use schedule::{Stage, System, FrameContext};
+use prefab::Prefab;
+use triggers::Trigger;
+use abilities::Ability;
+use joints::JointKind;
+use platforms::Conveyor;
 
 static REQUEST_WINDOW_WIDTH: u32 = 640;
 static REQUEST_WINDOW_HEIGHT: u32 = 480;
@@ -35,13 +54,29 @@ static REQUEST_WINDOW_HEIGHT: u32 = 480;
 enum ShellCommand {
   None,
   Exit,
-  DeleteEntity(usize),
+  DeleteEntity(String),
   Save(String),
   Load(String),
   DelAll,
   SetPhysPlay(bool),
   Show,
-  SetVelocity(usize, Vec2),
+  SetVelocity(String, Vec2),
+  Spawn(String, Prefab),
+  Attach(String, String),
+  Detach(String),
+  Name(String, String),
+  LoadTilemap(String, Vec2),
+  SetTrigger(String, Option<Trigger>),
+  Layers,
+  SetLayer(String, String, Vec<String>),
+  ShowLayer(String, bool),
+  Physics(Option<String>, Vec<f64>),
+  Controller(String, Option<(String, f64)>),
+  // true grants, false takes away
+  Grant(String, Ability, bool),
+  SetConveyor(String, Vec2),
+  Flip(String),
+  Joint(String, String, JointKind),
 }
 
 fn parse_input(input: &str, tx: &mpsc::Sender<ShellCommand>) {
@@ -54,17 +89,107 @@ fn parse_input(input: &str, tx: &mpsc::Sender<ShellCommand>) {
         },
         "del" => {
           match iter.next() {
-            Some(idstr) => {
-              match idstr.parse::<usize>() {
-                Ok(id) => {
-                  tx.send(ShellCommand::DeleteEntity(id));
-                },
-                Err(_) => {},
-              };
+            Some(entity) => {
+              tx.send(ShellCommand::DeleteEntity(entity.to_owned()));
             },
             None => {},
           }
         },
+        "name" => {
+          // name <id|name> <new name>
+          match (iter.next(), iter.next()) {
+            (Some(entity), Some(name)) => {
+              tx.send(ShellCommand::Name(entity.to_owned(), name.to_owned()));
+            },
+            _ => {
+              println!("name <id> <name>");
+            }
+          }
+        },
+        "attach" => {
+          // attach <child> <parent>
+          match (iter.next(), iter.next()) {
+            (Some(child), Some(parent)) => {
+              tx.send(ShellCommand::Attach(child.to_owned(), parent.to_owned()));
+            },
+            _ => {
+              println!("attach <child> <parent>");
+            }
+          }
+        },
+        "detach" => {
+          match iter.next() {
+            Some(entity) => {
+              tx.send(ShellCommand::Detach(entity.to_owned()));
+            },
+            None => {
+              println!("detach <id>");
+            }
+          }
+        },
+        "tilemap" => {
+          // tilemap <level> [x y], loads assets/<level>.lv with its bottom left at x, y
+          match iter.next() {
+            Some(level) => {
+              let coords: Vec<f64> = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+              let origin = match coords.len() {
+                2 => Vec2::new(coords[0], coords[1]),
+                _ => Vec2::new(0., 0.),
+              };
+              tx.send(ShellCommand::LoadTilemap(level.to_owned(), origin));
+            },
+            None => {
+              println!("Say a filename");
+            }
+          }
+        },
+        "trigger" => {
+          // trigger <id|name> checkpoint|hazard|camera|ladder|exit <level>|grant <ability>|none
+          let entity = iter.next();
+          let trigger = match (iter.next(), iter.next()) {
+            (Some("checkpoint"), _) => Some(Some(Trigger::Checkpoint)),
+            (Some("hazard"), _) => Some(Some(Trigger::Hazard)),
+            (Some("camera"), _) => Some(Some(Trigger::CameraZone)),
+            (Some("ladder"), _) => Some(Some(Trigger::Ladder)),
+            (Some("exit"), Some(level)) => Some(Some(Trigger::LevelExit(level.to_owned()))),
+            (Some("grant"), Some(ability)) => parse_ability(ability, iter.next()).map(|a| Some(Trigger::Grant(a))),
+            (Some("none"), _) => Some(None),
+            _ => None,
+          };
+          match (entity, trigger) {
+            (Some(entity), Some(trigger)) => {
+              tx.send(ShellCommand::SetTrigger(entity.to_owned(), trigger));
+            },
+            _ => {
+              println!("trigger <id> checkpoint|hazard|camera|ladder|exit <level>|grant <ability>|none");
+            }
+          }
+        },
+        "layers" => {
+          tx.send(ShellCommand::Layers);
+        },
+        "layer" => {
+          // layer <id|name> <layer> [mask layers...]
+          match (iter.next(), iter.next()) {
+            (Some(entity), Some(layer)) => {
+              let mask = iter.map(|s| s.to_owned()).collect();
+              tx.send(ShellCommand::SetLayer(entity.to_owned(), layer.to_owned(), mask));
+            },
+            _ => {
+              println!("layer <id> <layer> [mask layers...]");
+            }
+          }
+        },
+        "show" | "hide" => {
+          match iter.next() {
+            Some(layer) => {
+              tx.send(ShellCommand::ShowLayer(layer.to_owned(), command == "show"));
+            },
+            None => {
+              println!("{} <layer>", command);
+            }
+          }
+        },
         "save" => {
           match iter.next() {
             Some(filename) => {
@@ -102,33 +227,126 @@ fn parse_input(input: &str, tx: &mpsc::Sender<ShellCommand>) {
             None => {}
           }
         },
-        "setvel" => {
-          let mut id: usize;
-          let mut x: f64;
-          let mut y: f64;
-
+        "physics" => {
+          // physics [gravity x y|step s|substeps n|terminal v|killplane [y]|joints n]
+          let field = iter.next().map(|s| s.to_owned());
+          let values = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+          tx.send(ShellCommand::Physics(field, values));
+        },
+        "controller" => {
+          // controller <id|name> [field value]
           match iter.next() {
-            None => {},
-            Some(idstr) => {
-              match idstr.parse::<usize>() {
-                Err(_) => {},
-                Ok(id) => {
+            Some(entity) => {
+              let field = iter.next().map(|s| s.to_owned());
+              let value = iter.next().and_then(|s| s.parse::<f64>().ok());
+              match (field, value) {
+                (Some(field), Some(value)) => {
+                  tx.send(ShellCommand::Controller(entity.to_owned(), Some((field, value))));
+                },
+                (None, _) => {
+                  tx.send(ShellCommand::Controller(entity.to_owned(), None));
+                },
+                _ => {
+                  println!("controller <id> [field value]");
+                }
+              }
+            },
+            None => {
+              println!("controller <id> [field value]");
+            }
+          }
+        },
+        "grant" | "revoke" => {
+          // grant|revoke <id|name> airjump [count]|dash
+          let entity = iter.next();
+          let ability = iter.next().and_then(|a| parse_ability(a, iter.next()));
+          match (entity, ability) {
+            (Some(entity), Some(ability)) => {
+              tx.send(ShellCommand::Grant(entity.to_owned(), ability, command == "grant"));
+            },
+            _ => {
+              println!("{} <id> airjump [count]|dash", command);
+            }
+          }
+        },
+        "conveyor" => {
+          // conveyor <id|name> <x> <y>, 0 0 turns it off
+          let entity = iter.next();
+          let speed: Vec<f64> = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+          match entity {
+            Some(entity) if speed.len() == 2 => {
+              tx.send(ShellCommand::SetConveyor(entity.to_owned(), Vec2::new(speed[0], speed[1])));
+            },
+            _ => {
+              println!("conveyor <id> <x> <y>");
+            }
+          }
+        },
+        "flip" => {
+          // flip <id|name>, turns a lever or any other logic on or off
           match iter.next() {
-            None => {},
-            Some(xstr) => {
-              match xstr.parse::<f64>() {
-                Err(_) => {},
-                Ok(x) => {
+            Some(entity) => {
+              tx.send(ShellCommand::Flip(entity.to_owned()));
+            },
+            None => {
+              println!("flip <id>");
+            }
+          }
+        },
+        "joint" => {
+          // joint <id|name> <id|name> rope|rod|spring <stiffness> <damping>, as far apart as they are
+          let (a, b) = (iter.next(), iter.next());
+          let kind = match iter.next() {
+            Some("rope") => Some(JointKind::Rope),
+            Some("rod") => Some(JointKind::Rod),
+            Some("spring") => {
+              let values: Vec<f64> = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+              if values.len() == 2 {
+                Some(JointKind::Spring { stiffness: values[0], damping: values[1] })
+              } else {
+                None
+              }
+            },
+            _ => None,
+          };
+          match (a, b, kind) {
+            (Some(a), Some(b), Some(kind)) => {
+              tx.send(ShellCommand::Joint(a.to_owned(), b.to_owned(), kind));
+            },
+            _ => {
+              println!("joint <id> <id> rope|rod|spring <stiffness> <damping>");
+            }
+          }
+        },
+        "setvel" => {
+          // setvel <id|name> <x> <y>
+          let entity = iter.next();
+          let coords: Vec<f64> = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+          match entity {
+            Some(entity) if coords.len() == 2 => {
+              tx.send(ShellCommand::SetVelocity(entity.to_owned(), Vec2::new(coords[0], coords[1])));
+            },
+            _ => {
+              println!("setvel <id> <x> <y>");
+            }
+          }
+        },
+        "spawn" => {
+          // spawn <prefab> [x y]
           match iter.next() {
-            None => {},
-            Some(ystr) => {
-              match ystr.parse::<f64>() {
-                Err(_) => {},
-                Ok(y) => {
-                  tx.send(ShellCommand::SetVelocity(id, Vec2::new(x, y)));
-                },
-          } }, } }, } }, } }, } }, };
-        }
+            Some(name) => {
+              let coords: Vec<f64> = iter.filter_map(|s| s.parse::<f64>().ok()).collect();
+              let overrides = match coords.len() {
+                2 => Prefab::at(Vec2::new(coords[0], coords[1])),
+                _ => Prefab::default(),
+              };
+              tx.send(ShellCommand::Spawn(name.to_owned(), overrides));
+            },
+            None => {
+              println!("Say a prefab");
+            }
+          }
+        },
         _ => {
           println!("I didn't understand {}", input);
           tx.send(ShellCommand::None);
@@ -139,6 +357,23 @@ fn parse_input(input: &str, tx: &mpsc::Sender<ShellCommand>) {
   };
 }
 
+// airjump [count] or dash
+fn parse_ability(name: &str, arg: Option<&str>) -> Option<Ability> {
+  match name {
+    "airjump" => Some(Ability::AirJumps(arg.and_then(|n| n.parse().ok()).unwrap_or(1))),
+    "dash" => Some(Ability::Dash),
+    _ => None,
+  }
+}
+
+// Shell commands take either a numeric ID or an entity name
+fn resolve_entity(world: &World, entity: &str) -> Option<usize> {
+  let id = world.resolve(entity);
+  if id.is_none() {
+    println!("No entity {}", entity);
+  }
+  id
+}
 
 fn main() {
   // sdl setup
@@ -190,10 +425,15 @@ fn main() {
   let mut editor = Editor::new();
   // let mut world = World::new(&mut renderer, Vec2::new(640., 480.));
 
-  let sim_dt = time::Duration::from_millis(10);
+  let mut schedule = create_schedule();
+  schedule.add(Stage::Render, "editor", System::Frame(Box::new(
+    move |w: &mut World, ctx: &mut FrameContext| {
+      run_editor_systems(w, &mut editor, ctx.input, ctx.renderer, &mut font)
+    }
+  ))).after("render");
+
   let target_frame_time = time::Duration::from_millis(16);
   let mut last_time = time::Instant::now();
-  let mut dt_accum = time::Duration::new(0, 0);
 
   // debug stuff
   let mut frame_counter = 0;
@@ -221,7 +461,6 @@ fn main() {
     let dt = last_time.elapsed();
     last_time = time::Instant::now();
     assert!(dt >= target_frame_time);
-    dt_accum += dt;
 
     // input
     for event in event_pump.poll_iter() {
@@ -251,8 +490,10 @@ fn main() {
           ShellCommand::Exit => {
             world.alive = false;
           },
-          ShellCommand::DeleteEntity(id) => {
-            world.delete_entity(id);
+          ShellCommand::DeleteEntity(entity) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              world.delete_entity(id);
+            }
           },
           ShellCommand::Save(filename) => {
             let _ = world.save(&filename);
@@ -278,10 +519,161 @@ fn main() {
           ShellCommand::Show => {
             // TODO print world state
           },
-          ShellCommand::SetVelocity(id, vel) => {
-            println!("Setting vel for {} to ({}, {})", id, vel.x, vel.y);
-            world.velocities.insert(id, vel);
-          }
+          ShellCommand::SetVelocity(entity, vel) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              println!("Setting vel for {} to ({}, {})", id, vel.x, vel.y);
+              world.velocities.insert(id, vel);
+            }
+          },
+          ShellCommand::Spawn(name, overrides) => {
+            match world.instantiate_prefab(&name, overrides) {
+              Ok(id) => println!("Spawned {} as {}", name, id),
+              Err(e) => println!("Couldn't spawn {}: {}", name, e),
+            }
+          },
+          ShellCommand::Attach(child, parent) => {
+            if let (Some(child), Some(parent)) = (resolve_entity(&world, &child), resolve_entity(&world, &parent)) {
+              world.attach(child, parent);
+            }
+          },
+          ShellCommand::Detach(entity) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              world.detach(id);
+            }
+          },
+          ShellCommand::LoadTilemap(level, origin) => {
+            match world.new_tilemap(&format!("assets/{}.lv", level), origin) {
+              Ok(id) => println!("Tilemap {} is {}", level, id),
+              Err(e) => println!("Couldn't load tilemap {}: {}", level, e),
+            }
+          },
+          ShellCommand::SetTrigger(entity, trigger) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              match world.collisions.get(&id) {
+                Some(c) if c.sensor => {
+                  match trigger {
+                    Some(trigger) => world.triggers.insert(id, trigger),
+                    None => world.triggers.remove(&id),
+                  };
+                },
+                _ => println!("{} isn't a sensor", entity),
+              }
+            }
+          },
+          ShellCommand::Grant(entity, ability, grant) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              if grant {
+                world.grant(id, ability);
+              } else {
+                world.revoke(id, ability);
+              }
+            }
+          },
+          ShellCommand::SetConveyor(entity, velocity) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              if velocity == Vec2::new(0., 0.) {
+                world.conveyors.remove(&id);
+              } else {
+                world.conveyors.insert(id, Conveyor { velocity: velocity });
+              }
+              // Kept on the instance, stopped ones too so a conveyor prefab doesn't restart them on load
+              world.override_instance(id, |overrides| overrides.conveyor = Some(Conveyor { velocity: velocity }));
+            }
+          },
+          ShellCommand::Flip(entity) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              match world.logic.get_mut(&id) {
+                Some(l) => l.on = !l.on,
+                None => println!("{} has no logic", id),
+              }
+            }
+          },
+          ShellCommand::Joint(a, b, kind) => {
+            if let (Some(a), Some(b)) = (resolve_entity(&world, &a), resolve_entity(&world, &b)) {
+              println!("joined {} and {} with {}", a, b, world.new_joint(a, b, kind));
+            }
+          },
+          ShellCommand::Layers => {
+            for (i, name) in world.layers.names.iter().enumerate() {
+              let hidden = world.hidden_layers & (1 << i) != 0;
+              println!("{} {}{}", i, name, if hidden { " (hidden)" } else { "" });
+            }
+          },
+          ShellCommand::SetLayer(entity, layer, mask) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              // New layer names are added to the level's table
+              let bits = match world.layers.add(&layer) {
+                Ok(bits) => bits,
+                Err(e) => { println!("{}", e); 0 },
+              };
+              let mask_bits = if mask.is_empty() { Ok(layers::ALL_LAYERS) } else { world.layers.bits(&mask) };
+              let changed = match (world.collisions.get_mut(&id), mask_bits) {
+                (Some(collision), Ok(mask_bits)) if bits != 0 => {
+                  collision.layer = bits;
+                  collision.mask = mask_bits;
+                  Some(collision.clone())
+                },
+                (None, _) => {
+                  println!("{} has no collider", entity);
+                  None
+                },
+                (_, Err(e)) => {
+                  println!("{}", e);
+                  None
+                },
+                _ => None,
+              };
+              if let Some(collision) = changed {
+                world.override_instance(id, |overrides| overrides.collision = Some(collision));
+              }
+            }
+          },
+          ShellCommand::ShowLayer(layer, show) => {
+            match world.layers.bit(&layer) {
+              Some(bit) if show => world.hidden_layers &= !bit,
+              Some(bit) => world.hidden_layers |= bit,
+              None => println!("No layer {}", layer),
+            }
+          },
+          ShellCommand::Physics(field, values) => {
+            if let Some(field) = field {
+              if let Err(e) = world.physics.set(&field, &values) {
+                println!("{}", e);
+              }
+            }
+            println!("{:?}", world.physics);
+          },
+          ShellCommand::Controller(entity, change) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              let tuned = match world.controllers.get_mut(&id) {
+                Some(controller) => {
+                  let mut tuned = None;
+                  if let Some((field, value)) = change {
+                    match controller.set(&field, value) {
+                      Ok(()) => tuned = Some(controller.clone()),
+                      Err(e) => println!("{}", e),
+                    }
+                  }
+                  println!("{:?}", controller);
+                  tuned
+                },
+                None => {
+                  println!("{} has no controller", entity);
+                  None
+                },
+              };
+              if let Some(controller) = tuned {
+                world.override_instance(id, |overrides| overrides.controller = Some(controller));
+              }
+            }
+          },
+          ShellCommand::Name(entity, name) => {
+            if let Some(id) = resolve_entity(&world, &entity) {
+              if let Err(e) = world.set_name(id, &name) {
+                println!("{}", e);
+              }
+            }
+          },
         }
         print!(">> ");
         let _ = io::stdout().flush();
@@ -293,10 +685,21 @@ fn main() {
     renderer.set_draw_color(Color::RGBA(0,0,0,255));
     renderer.clear();
 
-    dt_accum = run_systems(&mut world, &input, &mut renderer, dt);
-    run_editor_systems(&mut world, &mut editor, &input, &mut renderer, &mut font);
+    schedule.run_frame(&mut world, &input, &mut renderer, dt);
 
     // loop finalizing
+    world.events.end_frame();
+    if let Some(level) = world.next_level.take() {
+      let filename = format!("assets/{}.air", level);
+      match World::from_file(Path::new(&filename), &mut renderer) {
+        Ok(w) => {
+          world = w;
+        },
+        _ => {
+          println!("No such level {}", level)
+        },
+      }
+    }
     renderer.present();
     prev_keys = input.keys;
     prev_mouse = input.mouse;
@@ -1,19 +1,139 @@
-use std::time;
+extern crate nalgebra;
+use self::nalgebra::{dot, norm};
+
 use std::collections::{HashSet, HashMap};
 
-use common::{Vec2};
+use common::{Vec2, AABB, Slope};
 use components::{Velocity, Position, World, Collision};
-use platforms::mover_blocks;
+use platforms::{mover_blocks, crumbling_platforms, surface_velocity};
+use hierarchy::propagate_transforms;
+use events::Event;
+use schedule::{Schedule, Stage};
+use layers::{WORLD, ALL_LAYERS, interacts};
+use tilemap::Tile;
+use triggers::Trigger;
+use query::{Shape, QueryFilter, shapes_near};
+use water::{Submersion, submersion};
 // use tilemap::Tilemap;
 
-const GRAVITY: f64 = 500.;
+// Margin around broadphase queries so surfaces exactly touching a box are found
+const SKIN: f64 = 0.01;
+
+// Tunables stored with the level, so levels can feel floaty or heavy
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PhysicsSettings {
+  pub gravity: Vec2,
+  // Fixed update step, in seconds
+  pub step: f64,
+  // Fixed steps per frame at most, time beyond that is dropped so a slow frame can't snowball
+  pub max_substeps: u32,
+  // Fastest a body can fall along gravity
+  pub terminal_velocity: f64,
+  // Bodies that fall below this height go back to their respawn point, or where they started
+  pub kill_plane: Option<f64>,
+  // Solver passes over the joints each step, more keeps long chains from stretching
+  pub joint_iterations: u32,
+}
+
+impl Default for PhysicsSettings {
+  fn default() -> PhysicsSettings {
+    PhysicsSettings {
+      gravity: Vec2::new(0., -500.),
+      step: 0.01,
+      max_substeps: 8,
+      terminal_velocity: 1000.,
+      kill_plane: Some(-50.),
+      joint_iterations: 8,
+    }
+  }
+}
+
+impl PhysicsSettings {
+  // Shell tuning, `killplane` with no value turns it off
+  pub fn set(&mut self, field: &str, values: &[f64]) -> Result<(), String> {
+    match (field, values.len()) {
+      ("gravity", 2) => self.gravity = Vec2::new(values[0], values[1]),
+      ("step", 1) if values[0] > 0. => self.step = values[0],
+      ("substeps", 1) if values[0] >= 1. => self.max_substeps = values[0] as u32,
+      ("terminal", 1) => self.terminal_velocity = values[0],
+      ("killplane", 1) => self.kill_plane = Some(values[0]),
+      ("killplane", 0) => self.kill_plane = None,
+      ("joints", 1) if values[0] >= 1. => self.joint_iterations = values[0] as u32,
+      _ => return Err(format!("Can't set {} to {:?}", field, values)),
+    }
+    Ok(())
+  }
+}
+
+// How a body moves, or for obstacles, how their surface treats whatever stands on them.
+// Friction and restitution are combined from the body and the surface, gravity scale
+// and air drag only matter on bodies.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Material {
+  pub gravity_scale: f64,
+  // Rate (per second) at which sliding on the ground dies out
+  pub friction: f64,
+  // Same while in the air
+  pub air_drag: f64,
+  // Fraction of the impact speed kept as a bounce
+  pub restitution: f64,
+}
+
+impl Default for Material {
+  fn default() -> Material {
+    Material {
+      gravity_scale: 1.,
+      friction: 20.,
+      air_drag: 0.,
+      restitution: 0.,
+    }
+  }
+}
+
+// Bounces slower than this settle instead, so bouncy things come to rest
+const MIN_BOUNCE_SPEED: f64 = 5.;
+
+fn material(w: &World, id: usize) -> Material {
+  w.materials.get(&id).cloned().unwrap_or_default()
+}
+
+fn combined_friction(body: &Material, surface: &Material) -> f64 {
+  (body.friction * surface.friction).sqrt()
+}
+
+// How well a body standing on its support can push off it, 1 on default materials.
+// Controllers scale their ground acceleration by it, so ice is slippery to walk on too.
+pub fn grip(w: &World, id: usize) -> f64 {
+  match w.supports.get(&id) {
+    Some(support) => {
+      let default = Material::default();
+      combined_friction(&material(w, id), &material(w, *support)) / combined_friction(&default, &default)
+    },
+    None => 1.,
+  }
+}
 
-fn camera_follow(camera_pos: &mut Position, following_pos: &Position) {
-  camera_pos.x = following_pos.x;
+// Velocity after bouncing off a surface, zero when it's too slow to bounce
+fn bounce(speed: f64, body: &Material, surface: &Material) -> f64 {
+  let bounced = -speed * body.restitution.max(surface.restitution);
+  if bounced.abs() < MIN_BOUNCE_SPEED { 0. } else { bounced }
 }
 
-fn movement_update(position: &Position, velocity: &Velocity, dt_seconds: f64) -> (Position, Velocity) {
-  let next_vel = velocity - Vec2::new(0., GRAVITY * dt_seconds);
+fn movement_update(settings: &PhysicsSettings, material: &Material, airborne: bool, position: &Position, velocity: &Velocity, dt_seconds: f64) -> (Position, Velocity) {
+  let mut next_vel = velocity + settings.gravity * material.gravity_scale * dt_seconds;
+  if airborne {
+    next_vel *= (-material.air_drag * dt_seconds).exp();
+  }
+  let g = norm(&settings.gravity);
+  if g > 0. {
+    let down = settings.gravity / g;
+    let fall = dot(&next_vel, &down);
+    if fall > settings.terminal_velocity {
+      next_vel -= down * (fall - settings.terminal_velocity);
+    }
+  }
   let dpos = next_vel * dt_seconds;
   let next = *position + dpos;
   (next, next_vel)
@@ -26,32 +146,301 @@ struct UpdateContainer {
   next_vel: Vec2,
 }
 
+// Everything solid that could touch `region`, except the mover, anything attached to it
+// and anything on layers it doesn't collide with
+fn obstacles_near(w: &World, mover_id: usize, region: &AABB) -> Vec<Shape> {
+  let (layer, mask) = match w.collisions.get(&mover_id) {
+    Some(c) => (c.layer, c.mask),
+    None => (WORLD, ALL_LAYERS),
+  };
+  let filter = QueryFilter {
+    layer: layer,
+    mask: mask,
+    exclude: Some(mover_id),
+    ..QueryFilter::default()
+  };
+  let mut shapes = shapes_near(w, region, &filter);
+  shapes.extend(ladder_tops(w, region, &filter));
+  shapes
+}
+
+// Ladders aren't solid, the controller climbs them, but bodies can stand on their tops
+// like one-way platforms: on ladder sensors, and on ladder tiles with no ladder above
+fn ladder_tops(w: &World, region: &AABB, filter: &QueryFilter) -> Vec<Shape> {
+  let mut tops = Vec::new();
+  for id in &w.entities {
+    if let Some((pos, coll)) = w.get_collider_entity(*id) {
+      if w.triggers.get(id) == Some(&Trigger::Ladder) && interacts(filter.layer, filter.mask, coll.layer, coll.mask) {
+        tops.push(Shape { id: *id, aabb: coll.offset(*pos), one_way: true, slope: None });
+      }
+    }
+    if let (Some(tilemap), Some(origin)) = (w.tilemaps.get(id), w.positions.get(id)) {
+      if !interacts(filter.layer, filter.mask, tilemap.layer, ALL_LAYERS) {
+        continue;
+      }
+      for (x, y, tile) in tilemap.intersects_box(&region.offset(-origin)) {
+        if tile == Tile::Ladder && tilemap.get_tile(x, y + 1) != Tile::Ladder {
+          tops.push(Shape { id: *id, aabb: tilemap.tile_aabb(x, y).offset(*origin), one_way: true, slope: None });
+        }
+      }
+    }
+  }
+  tops
+}
+
+fn top(aabb: &AABB) -> f64 {
+  aabb.center.y + aabb.half_size.y
+}
+
+// Ramps are walked on by the surface logic in physics_step. As boxes they only block
+// from below and at their tall sides, the sloped part is open to the sweeps.
+fn slope_blocks(slope: &Slope, aabb: &AABB, mover_abs: &AABB, d: Vec2) -> bool {
+  let (bottom, height) = (aabb.bottom_left().y, aabb.half_size.y * 2.);
+  let mover_bottom = mover_abs.bottom_left().y;
+  if d.y > 0. {
+    mover_abs.top_right().y <= bottom + 1e-9
+  } else if d.x < 0. {
+    mover_abs.bottom_left().x >= aabb.top_right().x - 1e-9 && mover_bottom < bottom + slope.right * height - 1e-9
+  } else if d.x > 0. {
+    mover_abs.top_right().x <= aabb.bottom_left().x + 1e-9 && mover_bottom < bottom + slope.left * height - 1e-9
+  } else {
+    false
+  }
+}
+
+fn dropping_through(w: &World, mover_id: usize) -> bool {
+  w.drop_through.contains_key(&mover_id)
+}
+
 fn find_collisions(w: &World, mover_id: usize, mover_collision: &Collision, mover_pos: &Position) -> Vec<(usize, Vec2)> {
   let mut collisions = Vec::new();
-  for id in &w.entities {
-    if *id == mover_id {
+  let mover_abs = mover_collision.offset(*mover_pos);
+  let dropping = dropping_through(w, mover_id);
+  for obstacle in obstacles_near(w, mover_id, &mover_abs.expanded(SKIN)) {
+    // One-way platforms only push out bodies that are mostly above them already
+    if obstacle.one_way && (dropping || mover_abs.center.y <= top(&obstacle.aabb)) {
       continue;
     }
-    if let Some((pos, coll)) = w.get_collider_entity(*id) {
-      let mover_abs = mover_collision.offset(*mover_pos);
-      let coll_abs = coll.offset(*pos);
-      if let Some(isect) = mover_abs.intersect(&coll_abs) {
-        collisions.push((*id, isect));
-      }
+    if obstacle.slope.is_some() {
+      continue;
+    }
+    if let Some(isect) = mover_abs.intersect(&obstacle.aabb) {
+      collisions.push((obstacle.id, isect));
     }
   }
   collisions
 }
 
-fn physics_step(w: &mut World, dt_seconds: f64, debug_collisions: &mut HashSet<usize>) {
-  // TODO physics (jump height, fall distance) seems a little inconsistent, do some tests
+// Earliest time of impact of the mover swept by `d`, with everything it hits at that time
+fn sweep_collisions(w: &World, mover_id: usize, mover_collision: &Collision, mover_pos: &Position, d: Vec2) -> Option<(f64, Vec<usize>)> {
+  let mover_abs = mover_collision.offset(*mover_pos);
+  let region = mover_abs.union(&mover_abs.offset(d)).expanded(SKIN);
+  let dropping = dropping_through(w, mover_id);
+  let mut first: Option<(f64, Vec<usize>)> = None;
+  for obstacle in obstacles_near(w, mover_id, &region) {
+    // One-way platforms only stop bodies falling onto them from above
+    if obstacle.one_way && (dropping || d.y >= 0. || mover_abs.bottom_left().y < top(&obstacle.aabb) - 1e-9) {
+      continue;
+    }
+    if let Some(ref slope) = obstacle.slope {
+      if !slope_blocks(slope, &obstacle.aabb, &mover_abs, d) {
+        continue;
+      }
+    }
+    if let Some(toi) = mover_abs.sweep(d, &obstacle.aabb) {
+      first = match first {
+        Some((best, mut hits)) => {
+          if (toi - best).abs() < 1e-9 {
+            if !hits.contains(&obstacle.id) {
+              hits.push(obstacle.id);
+            }
+            Some((best.min(toi), hits))
+          } else if toi < best {
+            Some((toi, vec![obstacle.id]))
+          } else {
+            Some((best, hits))
+          }
+        },
+        None => Some((toi, vec![obstacle.id])),
+      };
+    }
+  }
+  first.map(|(toi, mut hits)| {
+    hits.sort();
+    (toi, hits)
+  })
+}
+
+// Ramp surface a mover can stand on
+struct Ground {
+  id: usize,
+  height: f64,
+  // How far the surface can drop away under a mover walking by `dx`, and still be followed
+  reach: f64,
+  normal: Vec2,
+}
+
+// Highest ramp surface under the mover's feet that it can reach from `bottom` when moving
+// `dx` sideways. The corner on the uphill side rests on the ramp, so the box never sinks into it.
+// Looks from `bottom` down, so falling through a whole ramp in one step still lands on it.
+fn ramp_under(w: &World, mover_id: usize, mover_abs: &AABB, bottom: f64, dx: f64) -> Option<Ground> {
+  let (x0, x1) = (mover_abs.bottom_left().x, mover_abs.top_right().x);
+  let fell = (bottom - mover_abs.bottom_left().y).max(0.);
+  let region = mover_abs.offset(Vec2::new(0., fell))
+    .union(&mover_abs.offset(Vec2::new(0., -(dx.abs() * 4. + 1.))))
+    .expanded(SKIN);
+  let mut best: Option<Ground> = None;
+  for obstacle in obstacles_near(w, mover_id, &region) {
+    let slope = match obstacle.slope {
+      Some(slope) => slope,
+      None => continue,
+    };
+    let height = match slope.highest(&obstacle.aabb, x0, x1) {
+      Some(height) => height,
+      None => continue,
+    };
+    let reach = dx.abs() * slope.gradient(&obstacle.aabb).abs() + SKIN;
+    // Too high to step onto, that's the tall side of the ramp
+    if height > bottom + reach {
+      continue;
+    }
+    if best.as_ref().map_or(true, |g| height > g.height) {
+      best = Some(Ground { id: obstacle.id, height: height, reach: reach, normal: slope.normal(&obstacle.aabb) });
+    }
+  }
+  best
+}
+
+fn mass(w: &World, id: usize) -> f64 {
+  *w.masses.get(&id).unwrap_or(&1.)
+}
+
+struct AxisMove {
+  moved: Vec2,
+  hits: Vec<usize>,
+  // Something stopped the mover short of its full displacement
+  blocked: bool,
+}
+
+// Sweep the mover by `d` along one axis. Moving bodies in the way get pushed along, sharing
+// the rest of the displacement by mass ratio, and the two end up with a common velocity.
+// Pushed bodies don't push further, whatever they hit stops the whole chain.
+fn sweep_and_push(w: &mut World, updates: &mut HashMap<usize, UpdateContainer>, mover_id: usize, mover_collision: &Collision, pos: Vec2, d: Vec2, axis: usize) -> AxisMove {
+  let (toi, hits) = match sweep_collisions(w, mover_id, mover_collision, &pos, d) {
+    Some(hit) => hit,
+    None => return AxisMove { moved: d, hits: Vec::new(), blocked: false },
+  };
+  let contact = d * toi;
+  let remaining = d - contact;
+  if !hits.iter().all(|id| updates.contains_key(id)) {
+    return AxisMove { moved: contact, hits: hits, blocked: true };
+  }
+  // Only just reached a moving body, the push happens next step
+  if remaining[axis] == 0. {
+    return AxisMove { moved: contact, hits: hits, blocked: false };
+  }
+
+  let mover_mass = mass(w, mover_id);
+  let hit_mass: f64 = hits.iter().map(|id| mass(w, *id)).sum();
+  let push = remaining * (mover_mass / (mover_mass + hit_mass));
+
+  // Everything hit moves by the same amount, as far as the most obstructed one can go
+  let mut fraction: f64 = 1.;
+  for id in &hits {
+    if let (Some(coll), Some(hit_pos)) = (w.collisions.get(id), w.positions.get(id)) {
+      if let Some((hit_toi, _)) = sweep_collisions(w, *id, coll, hit_pos, push) {
+        fraction = fraction.min(hit_toi);
+      }
+    }
+  }
+  let pushed = push * fraction;
+  let blocked = fraction < 1.;
+
+  let mover_vel = updates[&mover_id].next_vel[axis];
+  let hit_momentum: f64 = hits.iter().map(|id| mass(w, *id) * updates[id].next_vel[axis]).sum();
+  let common_vel = if blocked { 0. } else { (mover_mass * mover_vel + hit_momentum) / (mover_mass + hit_mass) };
+  for id in &hits {
+    if let Some(p) = w.positions.get_mut(id) {
+      *p += pushed;
+    }
+    let update = updates.get_mut(id).unwrap();
+    update.pos += pushed;
+    update.next_pos += pushed;
+    update.next_vel[axis] = common_vel;
+  }
+  updates.get_mut(&mover_id).unwrap().next_vel[axis] = common_vel;
+
+  AxisMove { moved: contact + pushed, hits: hits, blocked: blocked }
+}
+
+fn physics_step(w: &mut World, dt_seconds: f64) {
   let mut move_updates: HashMap<usize, UpdateContainer> = HashMap::new();
   let mut ground_updates: HashMap<usize, bool> = HashMap::new();
+  let mut normal_updates: HashMap<usize, Vec2> = HashMap::new();
+  // What each grounded body stands on
+  let mut support_updates: HashMap<usize, usize> = HashMap::new();
+  // Side of the wall each body ran into, -1 left or 1 right
+  let mut wall_updates: HashMap<usize, f64> = HashMap::new();
+  let mut submerged_updates: HashMap<usize, Submersion> = HashMap::new();
+  // (body, launcher) for every launch this step
+  let mut launches: Vec<(usize, usize)> = Vec::new();
+  // (mover, other) pairs touching during this step
+  let mut contacts: HashSet<(usize, usize)> = HashSet::new();
+  let mut events: Vec<Event> = Vec::new();
+
+  for remaining in w.drop_through.values_mut() {
+    *remaining -= dt_seconds;
+  }
+  // Where new bodies start, to respawn them there
+  let started: Vec<(usize, Vec2)> = w.velocities.keys()
+    .filter(|id| !w.starts.contains_key(id))
+    .filter_map(|id| w.positions.get(id).map(|pos| (*id, *pos)))
+    .collect();
+  w.starts.extend(started);
+  w.drop_through.retain(|_, remaining| *remaining > 0.);
 
   // calculate potential next state
   for id in &w.entities {
+    // Attached entities are moved by their parent
+    if w.parents.contains_key(id) {
+      continue;
+    }
     if let Some((pos, vel)) = w.get_moving_entity(*id) {
-      let (next_pos, next_vel) = movement_update(pos, vel, dt_seconds);
+      let grounded = w.groundables.get(id) == Some(&true);
+      let mut airborne = !grounded;
+      let mut body_material = material(w, *id);
+
+      // Liquids slow bodies down and hold them up, wherever they are
+      let submerged = w.collisions.get(id).and_then(|c| {
+        let filter = QueryFilter { layer: c.layer, mask: c.mask, ..QueryFilter::default() };
+        submersion(w, &c.offset(*pos), &filter)
+      });
+      if let Some(s) = submerged {
+        body_material.gravity_scale = s.liquid.gravity_scale_for(body_material.gravity_scale, s.fraction);
+        body_material.air_drag += s.liquid.drag * s.fraction;
+        // so the drag works on the bottom too
+        airborne = true;
+        submerged_updates.insert(*id, s);
+      }
+      match (w.submerged.get(id), submerged) {
+        (None, Some(s)) if s.liquid.splash => {
+          events.push(Event::Splash { id: *id, liquid: s.id, at: *pos, entering: true });
+        },
+        (Some(s), None) if s.liquid.splash => {
+          events.push(Event::Splash { id: *id, liquid: s.id, at: *pos, entering: false });
+        },
+        _ => {},
+      }
+
+      if w.weightless.contains(id) {
+        body_material.gravity_scale = 0.;
+      }
+      let (mut next_pos, next_vel) = movement_update(&w.physics, &body_material, airborne, pos, vel, dt_seconds);
+      // Riders are carried by moving surfaces, their own velocity stays theirs.
+      // Underwater too, being airborne there is only about the drag.
+      if grounded {
+        next_pos += surface_velocity(w, *id) * dt_seconds;
+      }
       move_updates.insert(*id, UpdateContainer {
         pos: *pos,
         next_pos: next_pos,
@@ -63,60 +452,175 @@ fn physics_step(w: &mut World, dt_seconds: f64, debug_collisions: &mut HashSet<u
   }
 
   // detect && resolve collisions
+  // Each axis is swept separately (X then Y) from the start of the step, so fast bodies
+  // stop at the first surface in their way instead of tunneling through it.
+  // Movers resolve one at a time in ID order, each one's result is written back before the
+  // next, so later movers see where earlier ones ended up and nothing depends on map order.
   // TODO this does double the checks, could resolve pairs simultaneously for cheaper
-  // TODO how does this do on moving-to-moving collisions?
-  for (mover_id, ref mut update) in &mut move_updates {
+  let mut order: Vec<usize> = move_updates.keys().cloned().collect();
+  order.sort();
+  for mover_id in order {
     // Don't need to check collisions if the mover is not collidable
-    if let Some(mover_collision) = w.collisions.get(&mover_id) {
-      // First update and test X movement
-      let mut test_pos = Vec2::new(update.next_pos.x, update.pos.y);
-      let found_collisions = find_collisions(w, *mover_id, mover_collision, &test_pos);
-      let x_direction = (update.next_pos.x - update.pos.x).signum();
-      let mut backout: f64 = 0.;
-      if found_collisions.len() > 0 {
-        found_collisions.iter().map(|&(id, overlap)| {
-          debug_collisions.insert(id);
-          if overlap.x.signum() != x_direction && overlap.x.abs() > backout.abs() {
-            backout = overlap.x;
-          }
-        }).count();
-        test_pos.x += backout;
-      }
-
-      // Test and update Y movement
-      test_pos.y = update.next_pos.y;
-      let found_collisions = find_collisions(w, *mover_id, mover_collision, &test_pos);
-      if found_collisions.len() > 0 {
-        let y_direction = (update.next_pos.y - update.pos.y).signum();
-        let mut backout: f64 = 0.;
-        found_collisions.iter().map(|&(id, overlap)| {
-          if overlap.y.signum() != y_direction && overlap.y.abs() > backout.abs() {
-            backout = overlap.y;
-          }
-          debug_collisions.insert(id);
-        }).count();
-        test_pos.y += backout;
+    let mover_collision = match w.collisions.get(&mover_id) {
+      Some(c) if !c.sensor => c.clone(),
+      _ => continue,
+    };
+    let (pos, next_pos) = (move_updates[&mover_id].pos, move_updates[&mover_id].next_pos);
+    let mut test_pos = pos;
+    let body_material = material(w, mover_id);
+
+    // First sweep X movement
+    let dx = Vec2::new(next_pos.x - pos.x, 0.);
+    let x_move = sweep_and_push(w, &mut move_updates, mover_id, &mover_collision, test_pos, dx, 0);
+    test_pos += x_move.moved;
+    if x_move.blocked {
+      let hit = x_move.hits[0];
+      let update = move_updates.get_mut(&mover_id).unwrap();
+      match w.launchers.get(&hit).and_then(|l| l.launch(update.next_vel)) {
+        Some(launched) => {
+          update.next_vel = launched;
+          launches.push((mover_id, hit));
+        },
+        None => {
+          wall_updates.insert(mover_id, dx.x.signum());
+          update.next_vel.x = bounce(update.next_vel.x, &body_material, &material(w, hit));
+        },
+      }
+    }
+    for id in x_move.hits {
+      contacts.insert((mover_id, id));
+    }
+
+    // Then sweep Y movement from there
+    let bottom_before_y = mover_collision.offset(test_pos).bottom_left().y;
+    let dy = Vec2::new(0., next_pos.y - pos.y);
+    let y_move = sweep_and_push(w, &mut move_updates, mover_id, &mover_collision, test_pos, dy, 1);
+    test_pos += y_move.moved;
+    if y_move.blocked {
+      let hit = y_move.hits[0];
+      let update = move_updates.get_mut(&mover_id).unwrap();
 
-        // Additional side effects of a vertical collision
-        if update.next_pos.y < update.pos.y {
+      // Additional side effects of a vertical collision
+      let surface = material(w, hit);
+      let launched = w.launchers.get(&hit).and_then(|l| l.launch(update.next_vel));
+      if let Some(launched) = launched {
+        update.next_vel = launched;
+        launches.push((mover_id, hit));
+      } else if dy.y < 0. {
+        let bounced = bounce(update.next_vel.y, &body_material, &surface);
+        if bounced > 0. {
+          update.next_vel.y = bounced;
+        } else {
           // landed
-          ground_updates.insert(*mover_id, true);
+          if w.groundables.get(&mover_id) != Some(&true) {
+            events.push(Event::Landed { id: mover_id, on: hit });
+          }
+          ground_updates.insert(mover_id, true);
+          normal_updates.insert(mover_id, Vec2::new(0., 1.));
+          support_updates.insert(mover_id, hit);
           update.next_vel.y = 0.;
-        } else {
-          // bonked your head
-          update.next_vel.y = update.next_vel.y.min(0.);
+        }
+      } else {
+        // bonked your head
+        events.push(Event::BonkedHead { id: mover_id, on: hit });
+        update.next_vel.y = bounce(update.next_vel.y.max(0.), &body_material, &surface).min(0.);
+      }
+    }
+    for id in y_move.hits {
+      contacts.insert((mover_id, id));
+    }
+
+    // Ramps: stand on the surface, climbing it or following it down while walking
+    if move_updates[&mover_id].next_vel.y <= 0. {
+      let feet = mover_collision.offset(test_pos);
+      let bottom = feet.bottom_left().y;
+      let was_grounded = w.groundables.get(&mover_id) == Some(&true);
+      if let Some(ground) = ramp_under(w, mover_id, &feet, bottom_before_y, dx.x) {
+        let mut landed = false;
+        if ground.height > bottom {
+          test_pos.y += ground.height - bottom;
+          landed = true;
+        } else if was_grounded && !y_move.blocked && ground.height >= bottom - ground.reach {
+          // Don't snap through anything solid on the way down
+          let drop = Vec2::new(0., ground.height - bottom);
+          match sweep_collisions(w, mover_id, &mover_collision, &test_pos, drop) {
+            Some((toi, _)) => test_pos += drop * toi,
+            None => test_pos += drop,
+          }
+          landed = true;
+        }
+        if landed {
+          if !was_grounded && ground_updates.get(&mover_id) != Some(&true) {
+            events.push(Event::Landed { id: mover_id, on: ground.id });
+          }
+          ground_updates.insert(mover_id, true);
+          normal_updates.insert(mover_id, ground.normal);
+          support_updates.insert(mover_id, ground.id);
+          move_updates.get_mut(&mover_id).unwrap().next_vel.y = 0.;
+          contacts.insert((mover_id, ground.id));
+        }
+      }
+    }
+
+    // Push out of anything still overlapping, along the shallowest axis.
+    // Sweeps can't see those, it happens when something moved into the mover
+    for _ in 0..4 {
+      let found_collisions = find_collisions(w, mover_id, &mover_collision, &test_pos);
+      if found_collisions.is_empty() {
+        break;
+      }
+      let (id, overlap) = found_collisions[0];
+      contacts.insert((mover_id, id));
+      if overlap.x.abs() < overlap.y.abs() {
+        test_pos.x += overlap.x;
+      } else {
+        test_pos.y += overlap.y;
+        if overlap.y > 0. {
+          ground_updates.insert(mover_id, true);
+          normal_updates.insert(mover_id, Vec2::new(0., 1.));
+          support_updates.insert(mover_id, id);
+          let update = move_updates.get_mut(&mover_id).unwrap();
+          update.next_vel.y = update.next_vel.y.max(0.);
         }
       }
-      update.next_pos = test_pos;
     }
+    move_updates.get_mut(&mover_id).unwrap().next_pos = test_pos;
+    w.positions.insert(mover_id, test_pos);
   }
 
-  // don't allow fall below 0 (for testing)
-  for (id, ref mut update) in move_updates.iter_mut() {
-    if update.next_pos.y <= 0. {
-      update.next_pos.y = 0.;
-      update.next_vel.y = 0.;
-      ground_updates.insert(*id, true);
+  // contact begin/end against the previous step
+  let mut began: Vec<&(usize, usize)> = contacts.difference(&w.contacts).collect();
+  let mut ended: Vec<&(usize, usize)> = w.contacts.difference(&contacts).collect();
+  began.sort();
+  ended.sort();
+  for &&(id, other) in &began {
+    events.push(Event::CollisionBegin { id: id, other: other });
+  }
+  for &&(id, other) in &ended {
+    events.push(Event::CollisionEnd { id: id, other: other });
+  }
+  w.contacts = contacts;
+
+  // Launched controllers stop steering for a moment, so the launch isn't walked off
+  for (id, launcher) in launches {
+    let lockout = w.launchers[&launcher].lockout();
+    if let Some(controller) = w.controllers.get_mut(&id) {
+      controller.launched(lockout);
+    }
+    events.push(Event::Launched { id: id, by: launcher });
+  }
+  for event in events {
+    w.events.publish(event);
+  }
+
+  // Ground friction, from the body's material and the surface it's on
+  for (id, support) in &support_updates {
+    if w.steered.contains(id) {
+      continue;
+    }
+    if let Some(update) = move_updates.get_mut(id) {
+      let rate = combined_friction(&material(w, *id), &material(w, *support));
+      update.next_vel.x *= (-rate * dt_seconds).exp();
     }
   }
 
@@ -127,34 +631,364 @@ fn physics_step(w: &mut World, dt_seconds: f64, debug_collisions: &mut HashSet<u
     if let Some(grounded) = ground_updates.get(&id) {
       w.groundables.insert(id, *grounded);
     }
+    match normal_updates.get(&id) {
+      Some(normal) => w.ground_normals.insert(id, *normal),
+      None => w.ground_normals.remove(&id),
+    };
+    match wall_updates.get(&id) {
+      Some(side) => w.wall_contacts.insert(id, *side),
+      None => w.wall_contacts.remove(&id),
+    };
+    match support_updates.get(&id) {
+      Some(support) => w.supports.insert(id, *support),
+      None => w.supports.remove(&id),
+    };
+    match submerged_updates.get(&id) {
+      Some(s) => w.submerged.insert(id, *s),
+      None => w.submerged.remove(&id),
+    };
+  }
+
+  if let Some(kill_plane) = w.physics.kill_plane {
+    let mut fallen: Vec<usize> = w.velocities.keys()
+      .filter(|id| w.positions.get(id).map_or(false, |p| p.y < kill_plane))
+      .cloned()
+      .collect();
+    fallen.sort();
+    for id in fallen {
+      w.respawn(id);
+    }
   }
 }
 
-pub fn simulation_systems(w: &mut World, dt: time::Duration) -> time::Duration {
-  let sim_dt = time::Duration::from_millis(10);
-  let sim_dt_secs = sim_dt.as_secs() as f64 + (sim_dt.subsec_nanos() as f64 / 1000000000.);
-  let mut dt_accum = dt;
+// Keeps the debug highlight of touched colliders in sync with this frame's contact events
+fn contact_debug_system(w: &mut World, _: f64) {
+  let mut touched = w.statics_collisions.clone();
+  for event in w.events.this_frame() {
+    match *event {
+      Event::CollisionBegin { other, .. } => {
+        touched.insert(other);
+      },
+      Event::CollisionEnd { other, .. } => {
+        if !w.contacts.iter().any(|&(_, o)| o == other) {
+          touched.remove(&other);
+        }
+      },
+      _ => {},
+    }
+  }
+  w.statics_collisions = touched;
+}
+
+// physics_step publishes collision events, contact_debug consumes them later in the same frame
+pub fn register_systems(schedule: &mut Schedule) {
+  schedule.add_world(Stage::FixedUpdate, "transforms", |w, _| propagate_transforms(w));
+  schedule.add_world(Stage::FixedUpdate, "physics", physics_step).after("transforms");
+  schedule.add_world(Stage::FixedUpdate, "mover_blocks", mover_blocks).after("physics");
+  schedule.add_world(Stage::FixedUpdate, "crumbling_platforms", crumbling_platforms).after("physics");
+  schedule.add_world(Stage::LateUpdate, "contact_debug", contact_debug_system);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time;
+  use platforms::Conveyor;
+
+  fn simulate(w: &mut World, schedule: &mut Schedule, millis: u64) {
+    schedule.run_fixed(w, time::Duration::from_millis(millis));
+    contact_debug_system(w, 0.);
+  }
+
+  #[test]
+  fn landing_publishes_events() {
+    let mut w = World::new();
+    let ground = w.new_entity();
+    w.positions.insert(ground, Vec2::new(0., 5.));
+    w.collisions.insert(ground, Collision::new(Vec2::new(0., 0.), Vec2::new(10., 1.)));
+    let body = w.test_body(Vec2::new(0., 7.5), Vec2::new(0., -10.), Vec2::new(1., 1.));
+
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+    simulate(&mut w, &mut schedule, 100);
+    assert_eq!(w.groundables[&body], true);
+    assert!(w.events.this_frame().contains(&Event::Landed { id: body, on: ground }));
+    assert!(w.events.this_frame().contains(&Event::CollisionBegin { id: body, other: ground }));
+    assert!(w.statics_collisions.contains(&ground));
+
+    // Next frame the events have moved over, and resting doesn't land again
+    w.events.end_frame();
+    simulate(&mut w, &mut schedule, 100);
+    assert!(w.events.last_frame().contains(&Event::Landed { id: body, on: ground }));
+    assert!(w.events.this_frame().is_empty());
+  }
+
+  #[test]
+  fn running_into_walls_records_the_side() {
+    let mut w = World::new();
+    let wall = w.new_entity();
+    w.positions.insert(wall, Vec2::new(5., 0.));
+    w.collisions.insert(wall, Collision::new(Vec2::new(0., 0.), Vec2::new(1., 10.)));
+    let body = w.test_body(Vec2::new(2., 0.), Vec2::new(200., 0.), Vec2::new(1., 1.));
+
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+    simulate(&mut w, &mut schedule, 10);
+    assert_eq!(w.wall_contacts.get(&body), Some(&1.));
+    // Not pushing any more, not on the wall
+    simulate(&mut w, &mut schedule, 10);
+    assert!(w.wall_contacts.get(&body).is_none());
+  }
+
+  fn thin_platform(w: &mut World, center: Vec2, width: f64) -> usize {
+    // 0.5 thick, the editor's min_axis
+    let id = w.new_entity();
+    w.positions.insert(id, center);
+    w.collisions.insert(id, Collision::new(Vec2::new(0., 0.), Vec2::new(width, 0.5) / 2.));
+    id
+  }
+
+  #[test]
+  fn falls_from_great_heights_onto_thin_platforms() {
+    for &(height, speed) in &[(100., 0.), (1000., 0.), (5000., -500.), (20000., -10000.)] {
+      for &half in &[0.25, 1.] {
+        let mut w = World::new();
+        // Let them go fast enough to skip many platform thicknesses per step
+        w.physics.terminal_velocity = 100000.;
+        let platform = thin_platform(&mut w, Vec2::new(0., 50.), 10.);
+        let id = w.test_body(Vec2::new(0., height), Vec2::new(0., speed), Vec2::new(half, half));
+        let mut schedule = Schedule::new();
+        register_systems(&mut schedule);
+
+        for _ in 0..2000 {
+          simulate(&mut w, &mut schedule, 10);
+          if w.groundables[&id] {
+            break;
+          }
+        }
+        let resting = 50. + 0.25 + half;
+        assert!(w.groundables[&id], "never landed from {}", height);
+        assert!((w.positions[&id].y - resting).abs() < 1e-6,
+          "from {} at {}: ended at {}, expected {}", height, speed, w.positions[&id].y, resting);
+        assert!(w.contacts.contains(&(id, platform)));
+      }
+    }
+  }
+
+  #[test]
+  fn falls_onto_ramps_without_tunnelling() {
+    use tilemap::{Tilemap, Tile};
+    for &speed in &[0., -1000., -10000.] {
+      // A lone 45 degree ramp tile from (2, 50) to (4, 52)
+      let mut tm = Tilemap::new(3, 26, 2.);
+      tm.set_tile(1, 25, Tile::SlopeUp);
+      let mut w = World::new();
+      w.physics.terminal_velocity = 100000.;
+      let map = w.new_entity();
+      w.positions.insert(map, Vec2::new(0., 0.));
+      w.tilemaps.insert(map, tm);
+      let id = w.test_body(Vec2::new(3., 100.), Vec2::new(0., speed), Vec2::new(0.25, 0.25));
+      let mut schedule = Schedule::new();
+      register_systems(&mut schedule);
+
+      for _ in 0..500 {
+        simulate(&mut w, &mut schedule, 10);
+        if w.groundables.get(&id) != Some(&false) {
+          break;
+        }
+      }
+      // Uphill corner on the surface
+      assert_eq!(w.groundables.get(&id), Some(&true), "fell through at {}", speed);
+      assert!((w.positions[&id].y - 51.5).abs() < 1e-6, "at {}: ended at {}", speed, w.positions[&id].y);
+    }
+  }
+
+  #[test]
+  fn fast_bodies_stop_at_thin_walls() {
+    let mut w = World::new();
+    let wall = w.new_entity();
+    w.positions.insert(wall, Vec2::new(20., 10.));
+    w.collisions.insert(wall, Collision::new(Vec2::new(0., 0.), Vec2::new(0.25, 10.)));
+    let id = w.test_body(Vec2::new(0., 10.), Vec2::new(5000., 0.), Vec2::new(1., 1.));
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+
+    simulate(&mut w, &mut schedule, 10);
+    assert!((w.positions[&id].x - (20. - 0.25 - 1.)).abs() < 1e-6);
+    assert_eq!(w.velocities[&id].x, 0.);
+  }
+
+  #[test]
+  fn pushing_shares_motion_by_mass() {
+    let mut w = World::new();
+    let pusher = w.test_body(Vec2::new(0., 1.), Vec2::new(10., 0.), Vec2::new(1., 1.));
+    let heavy = w.test_body(Vec2::new(2., 1.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    w.masses.insert(heavy, 3.);
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+
+    simulate(&mut w, &mut schedule, 10);
+    // 0.1 of travel, a quarter of which goes into the heavy body
+    assert!((w.positions[&heavy].x - 2.025).abs() < 1e-9);
+    assert!((w.positions[&pusher].x - 0.025).abs() < 1e-9);
+    assert!((w.velocities[&heavy].x - 2.5).abs() < 1e-9);
+    assert!((w.velocities[&pusher].x - 2.5).abs() < 1e-9);
+  }
+
+  #[test]
+  fn head_on_bodies_never_overlap() {
+    let mut w = World::new();
+    let left = w.test_body(Vec2::new(0., 1.), Vec2::new(300., 0.), Vec2::new(1., 1.));
+    let right = w.test_body(Vec2::new(10., 1.), Vec2::new(-500., 0.), Vec2::new(1., 1.));
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+
+    for _ in 0..10 {
+      simulate(&mut w, &mut schedule, 10);
+      assert!(w.positions[&right].x - w.positions[&left].x >= 2. - 1e-9);
+    }
+    // Equal masses end up moving together with the average velocity
+    assert!((w.velocities[&left].x - w.velocities[&right].x).abs() < 1e-9);
+    assert!((w.velocities[&left].x + 100.).abs() < 1e-9);
+  }
+
+  #[test]
+  fn one_way_platforms_only_block_from_above() {
+    let mut w = World::new();
+    thin_platform(&mut w, Vec2::new(0., 0.), 20.);
+    let platform = thin_platform(&mut w, Vec2::new(0., 10.), 10.);
+    w.collisions.get_mut(&platform).unwrap().one_way = true;
+    let id = w.test_body(Vec2::new(0., 2.), Vec2::new(0., 120.), Vec2::new(1., 1.));
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
 
+    // Jumps up through it and lands on top
+    for _ in 0..300 {
+      simulate(&mut w, &mut schedule, 10);
+    }
+    assert!(w.groundables[&id]);
+    assert!((w.positions[&id].y - 11.25).abs() < 1e-6);
 
-  let mut statics_collisions: HashSet<usize> = HashSet::new();
+    // Dropping through falls to the floor below
+    w.drop_through.insert(id, 0.2);
+    for _ in 0..300 {
+      simulate(&mut w, &mut schedule, 10);
+    }
+    assert!(w.groundables[&id]);
+    assert!(w.positions[&id].y < 10.);
+  }
 
+  #[test]
+  fn walks_up_and_down_ramps_without_leaving_the_ground() {
+    use tilemap::{Tilemap, Tile};
+    // Floor at 2, a 45 degree ramp up to 4, then a shallow one up to 6
+    let mut tm = Tilemap::new(10, 3, 2.);
+    for x in 0..10 {
+      tm.set_tile(x, 0, Tile::Solid);
+    }
+    tm.set_tile(3, 1, Tile::SlopeUp);
+    for x in 4..10 {
+      tm.set_tile(x, 1, Tile::Solid);
+    }
+    tm.set_tile(5, 2, Tile::ShallowUpLow);
+    tm.set_tile(6, 2, Tile::ShallowUpHigh);
+    for x in 7..10 {
+      tm.set_tile(x, 2, Tile::Solid);
+    }
+    let mut w = World::new();
+    let map = w.new_entity();
+    w.positions.insert(map, Vec2::new(0., 0.));
+    w.tilemaps.insert(map, tm);
+    let id = w.test_body(Vec2::new(2., 4.), Vec2::new(0., 0.), Vec2::new(1., 2.));
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+    simulate(&mut w, &mut schedule, 10);
 
-  while dt_accum >= sim_dt {
-    physics_step(w, sim_dt_secs, &mut statics_collisions);
-    mover_blocks(w, sim_dt_secs);
-    dt_accum -= sim_dt;
+    for &(speed, end_x, end_bottom) in &[(20., 17., 6.), (-20., 2., 2.)] {
+      while (w.positions[&id].x - end_x) * speed < 0. {
+        w.velocities.get_mut(&id).unwrap().x = speed;
+        simulate(&mut w, &mut schedule, 10);
+        assert!(w.groundables[&id], "left the ground at {:?}", w.positions[&id]);
+        assert_eq!(w.velocities[&id].y, 0.);
+      }
+      assert!((w.positions[&id].y - 2. - end_bottom).abs() < 1e-6);
+    }
   }
 
-  // update camera follow TODO have a camera follower component that knows about what to follow
-  if let (Some(ref mut camera), Some(ref player_pos)) =
-         (w.cameras.get_mut(&w.current_camera), w.positions.get(&w.current_player))
-  {
-    camera_follow(&mut camera.pos, player_pos)
+  #[test]
+  fn masked_out_layers_pass_through() {
+    let mut w = World::new();
+    thin_platform(&mut w, Vec2::new(0., 10.), 10.);
+    let solid = w.test_body(Vec2::new(-3., 20.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    let ghost = w.test_body(Vec2::new(3., 20.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    w.collisions.get_mut(&ghost).unwrap().mask = !WORLD;
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+
+    for _ in 0..200 {
+      simulate(&mut w, &mut schedule, 10);
+    }
+    assert!((w.positions[&solid].y - 11.25).abs() < 1e-6);
+    // Fell out of the level and came back to where it started
+    assert!(w.entities.contains(&ghost));
+    assert!(w.events.this_frame().contains(&Event::Respawned { id: ghost, at: Vec2::new(3., 20.) }));
   }
 
-  // TODO reactions (damage, animation, sound) to physics collisions?
+  #[test]
+  fn surface_materials_change_sliding_and_bouncing() {
+    let slide = |friction: f64| {
+      let mut w = World::new();
+      let floor = thin_platform(&mut w, Vec2::new(0., 0.), 1000.);
+      w.materials.insert(floor, Material { friction: friction, ..Material::default() });
+      let id = w.test_body(Vec2::new(0., 1.25), Vec2::new(20., 0.), Vec2::new(1., 1.));
+      let mut schedule = Schedule::new();
+      register_systems(&mut schedule);
+      for _ in 0..100 {
+        simulate(&mut w, &mut schedule, 10);
+      }
+      w.positions[&id].x
+    };
+    assert!(slide(0.1) > slide(20.) * 5.);
+
+    let mut w = World::new();
+    let mushroom = thin_platform(&mut w, Vec2::new(0., 0.), 10.);
+    w.materials.insert(mushroom, Material { restitution: 0.8, ..Material::default() });
+    let id = w.test_body(Vec2::new(0., 20.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    let mut schedule = Schedule::new();
+    register_systems(&mut schedule);
+    let mut bounced = false;
+    for _ in 0..100 {
+      simulate(&mut w, &mut schedule, 10);
+      bounced |= w.velocities[&id].y > 0.;
+    }
+    assert!(bounced);
+  }
 
-  w.statics_collisions = statics_collisions;
+  #[test]
+  fn conveyors_carry_riders() {
+    for &flooded in &[false, true] {
+      let mut w = World::new();
+      let belt = w.new_entity();
+      w.positions.insert(belt, Vec2::new(0., 0.));
+      w.collisions.insert(belt, Collision::new(Vec2::new(0., 0.), Vec2::new(50., 1.)));
+      w.conveyors.insert(belt, Conveyor { velocity: Vec2::new(10., 0.) });
+      let rider = w.test_body(Vec2::new(0., 2.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+      if flooded {
+        w.new_water(Vec2::new(0., 5.), Vec2::new(100., 8.));
+      }
+      let mut schedule = Schedule::new();
+      register_systems(&mut schedule);
 
-  dt_accum
+      simulate(&mut w, &mut schedule, 10);
+      assert_eq!(w.supports.get(&rider), Some(&belt));
+      let start = w.positions[&rider].x;
+      for _ in 0..100 {
+        simulate(&mut w, &mut schedule, 10);
+      }
+      // Carried 10 in a second, without picking up any speed of its own
+      assert!((w.positions[&rider].x - start - 10.).abs() < 1e-6, "flooded: {}", flooded);
+      assert_eq!(w.velocities[&rider].x, 0.);
+      assert_eq!(w.positions[&belt], Vec2::new(0., 0.));
+    }
+  }
 }
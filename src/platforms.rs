@@ -2,13 +2,35 @@ extern crate nalgebra;
 use self::nalgebra::{distance, dot, norm};
 
 use common::{Vec2};
-use components::{World, Position};
+use components::{World, Position, ID};
+use query::{QueryFilter, overlap_aabb};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MoverBlock {
   pub start: Vec2,
   pub end: Vec2,
   pub travel_time: f64,
+  // Only moves while this logic entity is on, always moves without one
+  #[serde(default)]
+  pub input: Option<ID>,
+}
+
+// A surface that carries whatever stands on it at `velocity`, without moving itself.
+// Conveyors go sideways, escalators along their slope.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Conveyor {
+  pub velocity: Vec2,
+}
+
+// How fast the ground under a body carries it along, conveyors and bodies it rides
+pub fn surface_velocity(world: &World, id: ID) -> Vec2 {
+  match world.supports.get(&id) {
+    Some(support) => {
+      world.conveyors.get(support).map_or(Vec2::new(0., 0.), |c| c.velocity)
+        + world.velocities.get(support).cloned().unwrap_or(Vec2::new(0., 0.))
+    },
+    None => Vec2::new(0., 0.),
+  }
 }
 
 fn mover_block(pos: &mut Position, mover: &MoverBlock, dt_seconds: f64) {
@@ -31,8 +53,203 @@ fn mover_block(pos: &mut Position, mover: &MoverBlock, dt_seconds: f64) {
 
 pub fn mover_blocks(world: &mut World, dt_seconds: f64) {
   for (id, block) in &world.mover_blocks {
+    if !block.input.map_or(true, |input| world.signal(input)) {
+      continue;
+    }
     if let Some(ref mut p) = world.positions.get_mut(id) {
       mover_block(p, block, dt_seconds);
     }
   }
 }
+
+// Throws whatever runs into it. Either way the body's controller can't steer for
+// `lockout` seconds, so the launch isn't walked away.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Launcher {
+  // Sets the velocity outright, however the body came in
+  Spring { velocity: Vec2, lockout: f64 },
+  // Reflects the velocity off `normal` (unit length), scaled by `strength`.
+  // Only from the side the normal points to.
+  Bumper { normal: Vec2, strength: f64, lockout: f64 },
+}
+
+impl Launcher {
+  // Velocity of a body that hit the launcher at `vel`, None if it doesn't launch it
+  pub fn launch(&self, vel: Vec2) -> Option<Vec2> {
+    match *self {
+      Launcher::Spring { velocity, .. } => Some(velocity),
+      Launcher::Bumper { normal, strength, .. } => {
+        let into = dot(&vel, &normal);
+        if into < 0. {
+          Some((vel - normal * (2. * into)) * strength)
+        } else {
+          None
+        }
+      },
+    }
+  }
+
+  pub fn lockout(&self) -> f64 {
+    match *self {
+      Launcher::Spring { lockout, .. } | Launcher::Bumper { lockout, .. } => lockout,
+    }
+  }
+
+  // For the debug arrow
+  pub fn direction(&self) -> Vec2 {
+    match *self {
+      Launcher::Spring { velocity, .. } => velocity,
+      Launcher::Bumper { normal, strength, .. } => normal * strength * 50.,
+    }
+  }
+}
+
+// Platforms that give way a while after being stood on, and come back later
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Crumbling {
+  // Seconds of shaking once something stands on it
+  pub delay: f64,
+  // Falls under gravity when it gives way, otherwise it just disappears
+  pub falls: bool,
+  // Seconds from giving way to being back where it was
+  pub respawn_time: f64,
+  #[serde(skip)]
+  pub state: CrumbleState,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CrumbleState {
+  Solid,
+  // Seconds shaking so far, and where it rests
+  Shaking { time: f64, home: Position },
+  // Not solid while falling or gone, `time` since it gave way
+  Falling { time: f64, home: Position, speed: Vec2 },
+  Gone { time: f64, home: Position },
+}
+
+impl Default for CrumbleState {
+  fn default() -> CrumbleState {
+    CrumbleState::Solid
+  }
+}
+
+impl Crumbling {
+  pub fn is_solid(&self) -> bool {
+    match self.state {
+      CrumbleState::Solid | CrumbleState::Shaking { .. } => true,
+      _ => false,
+    }
+  }
+
+  pub fn is_gone(&self) -> bool {
+    match self.state {
+      CrumbleState::Gone { .. } => true,
+      _ => false,
+    }
+  }
+
+  // Where it rests, while it's shaking, fallen or gone
+  pub fn home(&self) -> Option<Position> {
+    match self.state {
+      CrumbleState::Solid => None,
+      CrumbleState::Shaking { home, .. } | CrumbleState::Falling { home, .. } | CrumbleState::Gone { home, .. } => Some(home),
+    }
+  }
+
+  // Where to draw it relative to its position, it rattles while shaking
+  pub fn shake_offset(&self) -> Vec2 {
+    match self.state {
+      CrumbleState::Shaking { time, .. } => Vec2::new((time * 60.).sin() * 0.1, 0.),
+      _ => Vec2::new(0., 0.),
+    }
+  }
+}
+
+pub fn crumbling_platforms(world: &mut World, dt_seconds: f64) {
+  let gravity = world.physics.gravity;
+  let mut ids: Vec<ID> = world.crumbling.keys().cloned().collect();
+  ids.sort();
+  for id in ids {
+    let stood_on = world.supports.values().any(|support| *support == id);
+    let pos = match world.positions.get(&id) {
+      Some(pos) => *pos,
+      None => continue,
+    };
+    // Coming back waits until the spot is clear
+    let blocked = |home: Position| match world.collisions.get(&id) {
+      Some(coll) => {
+        let filter = QueryFilter { exclude: Some(id), ..QueryFilter::default() };
+        !overlap_aabb(world, &coll.offset(home), &filter).is_empty()
+      },
+      None => false,
+    };
+    let crumbling = &world.crumbling[&id];
+    let (next_state, next_pos) = match crumbling.state {
+      CrumbleState::Solid if stood_on => (CrumbleState::Shaking { time: 0., home: pos }, pos),
+      CrumbleState::Solid => (CrumbleState::Solid, pos),
+      CrumbleState::Shaking { time, home } if time + dt_seconds >= crumbling.delay => {
+        if crumbling.falls {
+          (CrumbleState::Falling { time: 0., home: home, speed: Vec2::new(0., 0.) }, pos)
+        } else {
+          (CrumbleState::Gone { time: 0., home: home }, pos)
+        }
+      },
+      CrumbleState::Shaking { time, home } => (CrumbleState::Shaking { time: time + dt_seconds, home: home }, pos),
+      CrumbleState::Falling { time, home, .. } | CrumbleState::Gone { time, home }
+        if time + dt_seconds >= crumbling.respawn_time && !blocked(home) => (CrumbleState::Solid, home),
+      CrumbleState::Falling { time, home, speed } => {
+        let speed = speed + gravity * dt_seconds;
+        (CrumbleState::Falling { time: time + dt_seconds, home: home, speed: speed }, pos + speed * dt_seconds)
+      },
+      CrumbleState::Gone { time, home } => (CrumbleState::Gone { time: time + dt_seconds, home: home }, pos),
+    };
+    world.positions.insert(id, next_pos);
+    if let Some(crumbling) = world.crumbling.get_mut(&id) {
+      crumbling.state = next_state;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  extern crate serde_json;
+  use super::*;
+  use std::time;
+  use components::Collision;
+  use physics;
+  use schedule::Schedule;
+
+  #[test]
+  fn platforms_crumble_under_riders_and_come_back() {
+    let mut w = World::new();
+    let platform = w.new_entity();
+    w.positions.insert(platform, Vec2::new(0., 0.));
+    w.collisions.insert(platform, Collision::new(Vec2::new(0., 0.), Vec2::new(2., 0.5)));
+    w.crumbling.insert(platform, Crumbling { delay: 0.5, falls: true, respawn_time: 2., state: CrumbleState::Solid });
+    let rider = w.test_body(Vec2::new(0., 1.5), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    w.physics.kill_plane = None;
+    let mut schedule = Schedule::new();
+    physics::register_systems(&mut schedule);
+    let mut run = |w: &mut World, seconds: f64| {
+      for _ in 0..((seconds * 100.).round() as usize) {
+        schedule.run_fixed(w, time::Duration::from_millis(10));
+      }
+    };
+
+    run(&mut w, 0.3);
+    assert!(w.crumbling[&platform].is_solid());
+    assert!(w.groundables[&rider]);
+    run(&mut w, 0.5);
+    // Gave way, both are falling and the rider isn't standing on it any more
+    assert!(!w.crumbling[&platform].is_solid());
+    assert!(!w.groundables[&rider]);
+    assert!(w.positions[&platform].y < 0.);
+    // Saved where it rests
+    let saved: World = serde_json::from_str(&w.to_json()).unwrap();
+    assert_eq!(saved.positions[&platform], Vec2::new(0., 0.));
+    assert_eq!(saved.positions[&rider], w.positions[&rider]);
+    run(&mut w, 2.);
+    assert_eq!(w.crumbling[&platform].state, CrumbleState::Solid);
+    assert_eq!(w.positions[&platform], Vec2::new(0., 0.));
+  }
+}
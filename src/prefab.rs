@@ -0,0 +1,138 @@
+extern crate serde_json;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use components::{Position, Collision, Velocity, Mass};
+use platforms::{MoverBlock, Conveyor, Crumbling, Launcher};
+use triggers::Trigger;
+use physics::Material;
+use controller::Controller;
+use abilities::{AirJumps, Dash};
+use water::Liquid;
+use logic::{Logic, Door};
+
+// A prefab is a set of components with default values, loaded from assets/<name>.prefab.
+// The same struct doubles as the per-instance overrides: any component set there wins.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Prefab {
+  pub position: Option<Position>,
+  pub collision: Option<Collision>,
+  pub velocity: Option<Velocity>,
+  pub groundable: Option<bool>,
+  pub player: Option<bool>,
+  pub mover_block: Option<MoverBlock>,
+  pub conveyor: Option<Conveyor>,
+  pub crumbling: Option<Crumbling>,
+  pub launcher: Option<Launcher>,
+  pub mass: Option<Mass>,
+  pub trigger: Option<Trigger>,
+  pub material: Option<Material>,
+  pub controller: Option<Controller>,
+  pub air_jumps: Option<AirJumps>,
+  pub dash: Option<Dash>,
+  pub liquid: Option<Liquid>,
+  pub logic: Option<Logic>,
+  pub door: Option<Door>,
+}
+
+// Stored on the level so that instances are rebuilt from the prefab file on load
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrefabInstance {
+  pub prefab: String,
+  #[serde(default)]
+  pub overrides: Prefab,
+}
+
+pub fn prefab_path(name: &str) -> String {
+  format!("assets/{}.prefab", name)
+}
+
+// Names of all prefabs available in assets/, for the editor palette
+pub fn list_prefabs() -> Vec<String> {
+  let mut names = Vec::new();
+  if let Ok(entries) = fs::read_dir(Path::new("assets")) {
+    for entry in entries.filter_map(|e| e.ok()) {
+      let path = entry.path();
+      if path.extension().map_or(false, |ext| ext == "prefab") {
+        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+          names.push(stem.to_owned());
+        }
+      }
+    }
+  }
+  names.sort();
+  names
+}
+
+impl Prefab {
+  pub fn from_file(path: &Path) -> Result<Prefab, io::Error> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+
+    serde_json::from_str(&contents)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+  }
+
+  pub fn at(position: Position) -> Prefab {
+    Prefab {
+      position: Some(position),
+      ..Prefab::default()
+    }
+  }
+
+  pub fn with_overrides(&self, overrides: &Prefab) -> Prefab {
+    Prefab {
+      position: overrides.position.or(self.position),
+      collision: overrides.collision.clone().or(self.collision.clone()),
+      velocity: overrides.velocity.or(self.velocity),
+      groundable: overrides.groundable.or(self.groundable),
+      player: overrides.player.or(self.player),
+      mover_block: overrides.mover_block.clone().or(self.mover_block.clone()),
+      conveyor: overrides.conveyor.or(self.conveyor),
+      crumbling: overrides.crumbling.clone().or(self.crumbling.clone()),
+      launcher: overrides.launcher.or(self.launcher),
+      mass: overrides.mass.or(self.mass),
+      trigger: overrides.trigger.clone().or(self.trigger.clone()),
+      material: overrides.material.or(self.material),
+      controller: overrides.controller.clone().or(self.controller.clone()),
+      air_jumps: overrides.air_jumps.clone().or(self.air_jumps.clone()),
+      dash: overrides.dash.clone().or(self.dash.clone()),
+      liquid: overrides.liquid.or(self.liquid),
+      logic: overrides.logic.clone().or(self.logic.clone()),
+      door: overrides.door.or(self.door),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use common::Vec2;
+
+  #[test]
+  fn overrides_win() {
+    let base = Prefab {
+      position: Some(Vec2::new(8., 4.)),
+      collision: Some(Collision::new(Vec2::new(0., 0.), Vec2::new(1., 2.))),
+      groundable: Some(false),
+      ..Prefab::default()
+    };
+    let merged = base.with_overrides(&Prefab::at(Vec2::new(1., 2.)));
+    assert_eq!(merged.position, Some(Vec2::new(1., 2.)));
+    assert_eq!(merged.collision.unwrap().half_size, Vec2::new(1., 2.));
+    assert_eq!(merged.groundable, Some(false));
+    assert!(merged.velocity.is_none());
+  }
+
+  #[test]
+  fn shipped_prefabs_parse() {
+    for name in list_prefabs() {
+      assert!(Prefab::from_file(Path::new(&prefab_path(&name))).is_ok(), "{}", name);
+    }
+  }
+}
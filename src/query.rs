@@ -0,0 +1,325 @@
+extern crate nalgebra;
+use self::nalgebra::{dot, norm};
+
+use common::{Vec2, AABB, Slope};
+use components::{World, ID};
+use hierarchy::is_ancestor;
+use layers::{LayerBits, ALL_LAYERS, interacts};
+use tilemap::Tile;
+use triggers::Trigger;
+
+// Spatial queries against colliders and tilemaps, for gameplay and AI code.
+// The physics step gathers its obstacles through here as well.
+
+// A single solid piece of the world: an entity's collider or one tile.
+// Tiles report the ID of their tilemap.
+#[derive(Debug, Clone)]
+pub struct Shape {
+  pub id: ID,
+  pub aabb: AABB,
+  pub one_way: bool,
+  pub slope: Option<Slope>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+  pub id: ID,
+  // Where the ray hit, for sweeps where the box's center is at the time of impact
+  pub point: Vec2,
+  // Surface normal at the hit, pointing back at the query
+  pub normal: Vec2,
+  pub distance: f64,
+}
+
+// Which shapes a query can see. `layer` is the querying thing's own layer,
+// so shapes whose masks ignore it are skipped too.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryFilter {
+  pub layer: LayerBits,
+  pub mask: LayerBits,
+  // Skip this entity and everything attached to it
+  pub exclude: Option<ID>,
+  pub sensors: bool,
+  // Skip anything with a velocity, only level geometry gets in the way
+  pub statics_only: bool,
+}
+
+impl Default for QueryFilter {
+  fn default() -> QueryFilter {
+    QueryFilter {
+      layer: ALL_LAYERS,
+      mask: ALL_LAYERS,
+      exclude: None,
+      sensors: false,
+      statics_only: false,
+    }
+  }
+}
+
+impl QueryFilter {
+  pub fn mask(mask: LayerBits) -> QueryFilter {
+    QueryFilter {
+      mask: mask,
+      ..QueryFilter::default()
+    }
+  }
+}
+
+// Shapes that pass the filter and could touch `region`
+pub fn shapes_near(w: &World, region: &AABB, filter: &QueryFilter) -> Vec<Shape> {
+  let mut shapes = Vec::new();
+  for id in &w.entities {
+    if let Some(excluded) = filter.exclude {
+      if *id == excluded || is_ancestor(w, excluded, *id) {
+        continue;
+      }
+    }
+    if filter.statics_only && w.velocities.contains_key(id) {
+      continue;
+    }
+    // Crumbled platforms and open doors aren't there
+    let solid = w.crumbling.get(id).map_or(true, |c| c.is_solid()) && !w.door_open(*id);
+    if let Some((pos, coll)) = w.get_collider_entity(*id) {
+      if solid && interacts(filter.layer, filter.mask, coll.layer, coll.mask) {
+        if !coll.sensor || filter.sensors {
+          shapes.push(Shape { id: *id, aabb: coll.offset(*pos), one_way: coll.one_way, slope: coll.slope });
+        }
+      }
+    }
+    if let (Some(tilemap), Some(origin)) = (w.tilemaps.get(id), w.positions.get(id)) {
+      if !interacts(filter.layer, filter.mask, tilemap.layer, ALL_LAYERS) {
+        continue;
+      }
+      for (x, y, tile) in tilemap.intersects_box(&region.offset(-origin)) {
+        if tile == Tile::Water || tile == Tile::Ladder {
+          continue;
+        }
+        shapes.push(Shape {
+          id: *id,
+          aabb: tilemap.tile_aabb(x, y).offset(*origin),
+          one_way: tile == Tile::OneWay,
+          slope: tile.slope(),
+        });
+      }
+    }
+  }
+  shapes
+}
+
+// Whether the box overlaps a ladder tile or ladder sensor
+pub fn touches_ladder(w: &World, aabb: &AABB, filter: &QueryFilter) -> bool {
+  for id in &w.entities {
+    if let Some((pos, coll)) = w.get_collider_entity(*id) {
+      if w.triggers.get(id) == Some(&Trigger::Ladder)
+        && interacts(filter.layer, filter.mask, coll.layer, coll.mask)
+        && aabb.intersect(&coll.offset(*pos)).is_some() {
+        return true;
+      }
+    }
+    if let (Some(tilemap), Some(origin)) = (w.tilemaps.get(id), w.positions.get(id)) {
+      let local = aabb.offset(-origin);
+      let ladder = tilemap.intersects_box(&local).iter()
+        .any(|&(x, y, tile)| tile == Tile::Ladder && local.intersect(&tilemap.tile_aabb(x, y)).is_some());
+      if ladder && interacts(filter.layer, filter.mask, tilemap.layer, ALL_LAYERS) {
+        return true;
+      }
+    }
+  }
+  false
+}
+
+// Counter-clockwise outline of the solid part of a shape
+fn outline(shape: &Shape) -> Vec<Vec2> {
+  let (bl, tr) = (shape.aabb.bottom_left(), shape.aabb.top_right());
+  match shape.slope {
+    Some(ref slope) => {
+      let height = tr.y - bl.y;
+      vec![bl, Vec2::new(tr.x, bl.y), Vec2::new(tr.x, bl.y + slope.right * height), Vec2::new(bl.x, bl.y + slope.left * height)]
+    },
+    None => vec![bl, Vec2::new(tr.x, bl.y), tr, Vec2::new(bl.x, tr.y)],
+  }
+}
+
+fn cross(o: Vec2, a: Vec2, b: Vec2) -> f64 {
+  (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+}
+
+// Counter-clockwise convex hull (monotone chain)
+fn convex_hull(mut points: Vec<Vec2>) -> Vec<Vec2> {
+  points.sort_by(|a, b| a.x.partial_cmp(&b.x).unwrap().then(a.y.partial_cmp(&b.y).unwrap()));
+  let mut hull: Vec<Vec2> = Vec::new();
+  for pass in 0..2 {
+    let start = hull.len();
+    let ordered: Vec<Vec2> = if pass == 0 { points.clone() } else { points.iter().rev().cloned().collect() };
+    for p in ordered {
+      while hull.len() >= start + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0. {
+        hull.pop();
+      }
+      hull.push(p);
+    }
+    hull.pop();
+  }
+  hull
+}
+
+// The outline grown by a box's half size, sweeping the box is casting its center against this
+fn grown_outline(shape: &Shape, half_size: Vec2) -> Vec<Vec2> {
+  let mut points = Vec::new();
+  for v in outline(shape) {
+    for &(sx, sy) in &[(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)] {
+      points.push(v + Vec2::new(sx * half_size.x, sy * half_size.y));
+    }
+  }
+  convex_hull(points)
+}
+
+// Ray against a convex counter-clockwise polygon (Cyrus-Beck), `dir` is unit length.
+// Returns the entry distance and normal, a ray starting inside hits at 0.
+fn ray_polygon(origin: Vec2, dir: Vec2, max_distance: f64, polygon: &[Vec2]) -> Option<(f64, Vec2)> {
+  let mut enter = 0.;
+  let mut leave = max_distance;
+  let mut normal = -dir;
+  for i in 0..polygon.len() {
+    let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+    let edge = b - a;
+    let length = norm(&edge);
+    if length < 1e-12 {
+      continue;
+    }
+    let n = Vec2::new(edge.y, -edge.x) / length;
+    let num = dot(&n, &(a - origin));
+    let den = dot(&n, &dir);
+    if den == 0. {
+      if num < 0. {
+        return None;
+      }
+    } else {
+      let t = num / den;
+      if den < 0. {
+        if t > enter {
+          enter = t;
+          normal = n;
+        }
+      } else if t < leave {
+        leave = t;
+      }
+    }
+    if enter > leave {
+      return None;
+    }
+  }
+  Some((enter, normal))
+}
+
+// One-way shapes only stop things coming down onto their top
+fn blocks(shape: &Shape, distance: f64, normal: Vec2) -> bool {
+  !shape.one_way || (distance > 0. && normal.y > 0.5)
+}
+
+fn region_between(a: &AABB, b: &AABB) -> AABB {
+  a.union(b).expanded(0.01)
+}
+
+// Nearest hit of `polygon_for(shape)` along a ray, over all shapes
+fn cast<F>(w: &World, origin: Vec2, d: Vec2, region: &AABB, filter: &QueryFilter, polygon_for: F) -> Option<Hit>
+  where F: Fn(&Shape) -> Vec<Vec2>
+{
+  let length = norm(&d);
+  let dir = if length > 0. { d / length } else { Vec2::new(0., 0.) };
+  let mut best: Option<Hit> = None;
+  for shape in shapes_near(w, region, filter) {
+    if let Some((distance, normal)) = ray_polygon(origin, dir, length, &polygon_for(&shape)) {
+      if !blocks(&shape, distance, normal) {
+        continue;
+      }
+      if best.map_or(true, |h| distance < h.distance) {
+        best = Some(Hit { id: shape.id, point: origin + dir * distance, normal: normal, distance: distance });
+      }
+    }
+  }
+  best
+}
+
+// First thing along a ray from `origin` in `direction`, up to `max_distance`
+pub fn raycast(w: &World, origin: Vec2, direction: Vec2, max_distance: f64, filter: &QueryFilter) -> Option<Hit> {
+  let length = norm(&direction);
+  if length == 0. {
+    return None;
+  }
+  segment(w, origin, origin + direction / length * max_distance, filter)
+}
+
+// First thing on the line from `from` to `to`
+pub fn segment(w: &World, from: Vec2, to: Vec2, filter: &QueryFilter) -> Option<Hit> {
+  let region = region_between(&AABB::new(from, Vec2::new(0., 0.)), &AABB::new(to, Vec2::new(0., 0.)));
+  cast(w, from, to - from, &region, filter, outline)
+}
+
+// Everything overlapping the box, sorted by ID
+pub fn overlap_aabb(w: &World, aabb: &AABB, filter: &QueryFilter) -> Vec<ID> {
+  let (x0, x1) = (aabb.bottom_left().x, aabb.top_right().x);
+  let mut ids: Vec<ID> = shapes_near(w, aabb, filter).into_iter()
+    .filter(|shape| {
+      if aabb.intersect(&shape.aabb).is_none() {
+        return false;
+      }
+      match shape.slope {
+        Some(ref slope) => slope.highest(&shape.aabb, x0, x1).map_or(false, |h| aabb.bottom_left().y < h),
+        None => true,
+      }
+    })
+    .map(|shape| shape.id)
+    .collect();
+  ids.sort();
+  ids.dedup();
+  ids
+}
+
+// First thing the box hits when moved by `d`
+pub fn sweep_aabb(w: &World, aabb: &AABB, d: Vec2, filter: &QueryFilter) -> Option<Hit> {
+  let region = region_between(aabb, &aabb.offset(d));
+  let half_size = aabb.half_size;
+  cast(w, aabb.center, d, &region, filter, |shape| grown_outline(shape, half_size))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use components::Collision;
+  use tilemap::Tilemap;
+  use layers::WORLD;
+
+  #[test]
+  fn queries_hit_colliders_and_tiles() {
+    let mut w = World::new();
+    let wall = w.new_entity();
+    w.positions.insert(wall, Vec2::new(10., 0.));
+    w.collisions.insert(wall, Collision::new(Vec2::new(0., 0.), Vec2::new(1., 5.)));
+    let mut tm = Tilemap::new(4, 1, 2.);
+    tm.set_tile(0, 0, Tile::Solid);
+    tm.set_tile(1, 0, Tile::SlopeUp);
+    let map = w.new_entity();
+    w.positions.insert(map, Vec2::new(0., -10.));
+    w.tilemaps.insert(map, tm);
+
+    let hit = raycast(&w, Vec2::new(0., 0.), Vec2::new(1., 0.), 100., &QueryFilter::default()).unwrap();
+    assert_eq!(hit.id, wall);
+    assert_eq!(hit.point, Vec2::new(9., 0.));
+    assert_eq!(hit.normal, Vec2::new(-1., 0.));
+    assert_eq!(hit.distance, 9.);
+    assert!(segment(&w, Vec2::new(0., 0.), Vec2::new(8., 0.), &QueryFilter::default()).is_none());
+    assert!(raycast(&w, Vec2::new(0., 0.), Vec2::new(1., 0.), 100., &QueryFilter::mask(!WORLD)).is_none());
+
+    // Straight down onto the ramp tile, which rises from 2 to 4 across x 2..4
+    let down = raycast(&w, Vec2::new(3., 0.), Vec2::new(0., -1.), 100., &QueryFilter::default()).unwrap();
+    assert_eq!(down.id, map);
+    assert!((down.point.y - -9.).abs() < 1e-9);
+    assert!(norm(&(down.normal - Vec2::new(-1., 1.) / 2f64.sqrt())) < 1e-9);
+
+    assert_eq!(overlap_aabb(&w, &AABB::new(Vec2::new(9., 0.), Vec2::new(1., 1.)), &QueryFilter::default()), vec![wall]);
+    let sweep = sweep_aabb(&w, &AABB::new(Vec2::new(1., 0.), Vec2::new(1., 1.)), Vec2::new(0., -20.), &QueryFilter::default()).unwrap();
+    assert_eq!(sweep.id, map);
+    assert!((sweep.point.y - -7.).abs() < 1e-9);
+    assert_eq!(sweep.normal, Vec2::new(0., 1.));
+  }
+}
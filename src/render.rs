@@ -1,8 +1,10 @@
 extern crate sdl2;
 
 use camera::Camera;
-use common::{Vec2, AABB};
+use common::{Vec2, AABB, Slope};
 use components::{Position, Collision, World};
+use tilemap::{Tilemap, Tile};
+use joints::JointKind;
 
 use std::path::Path;
 use std::fmt;
@@ -10,6 +12,7 @@ use std::fmt;
 use sdl2::image::{LoadSurface};
 use sdl2::pixels::Color;
 use sdl2::render::Renderer;
+use sdl2::rect::Point;
 
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -29,11 +32,16 @@ impl Rect {
   }
 }
 
+fn one_frame() -> u32 { 1 }
+
 #[derive(Serialize, Deserialize)]
 pub struct Sprite {
   aabb: AABB,
   source_rect: Rect,
   source_path: String,
+  // Number of frames laid out horizontally in the source image
+  #[serde(default = "one_frame")]
+  frames: u32,
   #[serde(skip)]
   texture: Option<sdl2::render::Texture>,
 }
@@ -46,18 +54,24 @@ fn load_texture(path: &str, renderer: &Renderer) -> (sdl2::render::Texture, u32,
 
 impl Sprite {
   pub fn new(renderer: &Renderer, tex_path: &str, aabb: AABB) -> Sprite {
+    Sprite::new_sheet(renderer, tex_path, aabb, 1)
+  }
+
+  // Shows the first frame of a horizontal sprite sheet
+  pub fn new_sheet(renderer: &Renderer, tex_path: &str, aabb: AABB, frames: u32) -> Sprite {
     let (tex, width, height) = load_texture(tex_path, renderer);
     Sprite {
       aabb: aabb,
-      source_rect: Rect::new(0, 0, width, height),
+      source_rect: Rect::new(0, 0, width / frames, height),
       source_path: tex_path.to_owned(),
+      frames: frames,
       texture: Some(tex),
     }
   }
 
   pub fn reload_assets(&mut self, renderer: &Renderer) {
     let (tex, width, height) = load_texture(&self.source_path[..], renderer);
-    self.source_rect = Rect::new(0, 0, width, height);
+    self.source_rect = Rect::new(0, 0, width / self.frames.max(1), height);
     self.texture = Some(tex);
   }
 
@@ -79,6 +93,34 @@ pub fn draw_rect(renderer: &mut Renderer, camera: &Camera, bl: Vec2, size: Vec2,
   let _ = renderer.fill_rect(rect);
 }
 
+pub fn draw_line(renderer: &mut Renderer, camera: &Camera, from: Vec2, to: Vec2, color: Color) {
+  let (a, b) = (camera.to_draw_rect(from, Vec2::new(0., 0.)), camera.to_draw_rect(to, Vec2::new(0., 0.)));
+  renderer.set_draw_color(color);
+  let _ = renderer.draw_line(Point::new(a.x(), a.y()), Point::new(b.x(), b.y()));
+}
+
+// Launch vectors, at a tenth of their speed
+fn draw_arrow(renderer: &mut Renderer, camera: &Camera, from: Vec2, v: Vec2, color: Color) {
+  let to = from + v / 10.;
+  let back = (from - to) / 4.;
+  let side = Vec2::new(-back.y, back.x) / 2.;
+  draw_line(renderer, camera, from, to, color);
+  draw_line(renderer, camera, to, to + back + side, color);
+  draw_line(renderer, camera, to, to + back - side, color);
+}
+
+// Ramps drawn as a row of columns up to the surface
+fn draw_slope(renderer: &mut Renderer, camera: &Camera, aabb: &AABB, slope: &Slope, color: Color) {
+  let columns = 8;
+  let width = aabb.half_size.x * 2. / columns as f64;
+  let bl = aabb.bottom_left();
+  for i in 0..columns {
+    let x = bl.x + width * i as f64;
+    let height = slope.height_at(aabb, x + width / 2.) - bl.y;
+    draw_rect(renderer, camera, Vec2::new(x, bl.y), Vec2::new(width, height), color);
+  }
+}
+
 fn draw_sprite(sprite: &Sprite, pos: &Position, renderer: &mut Renderer, cam: &Camera) {
   if let Some(ref tex) = sprite.texture {
     let bl = pos + sprite.aabb.center - sprite.aabb.half_size;
@@ -101,9 +143,45 @@ fn draw_physics(position: &Position, collision: &Collision, on_ground: bool, ren
   draw_rect(renderer, cam, collision.offset(*position).bottom_left(), collision.half_size * 2., draw_color);
 }
 
-fn draw_static(position: &Position, collision: &Collision, renderer: &mut Renderer, cam: &Camera, is_collided: bool) {
-  let draw_color = if is_collided { Color::RGBA(0, 255, 255, 255) } else { Color::RGBA(255, 255, 0, 255) };
-  draw_rect(renderer, cam, collision.offset(*position).bottom_left(), collision.half_size * 2., draw_color);
+fn draw_static(position: &Position, collision: &Collision, renderer: &mut Renderer, cam: &Camera, is_collided: bool, is_liquid: bool) {
+  let draw_color = if is_liquid {
+    Color::RGBA(40, 90, 255, 100)
+  } else if collision.sensor {
+    if is_collided { Color::RGBA(0, 255, 120, 120) } else { Color::RGBA(0, 160, 80, 80) }
+  } else if is_collided {
+    Color::RGBA(0, 255, 255, 255)
+  } else if collision.one_way {
+    Color::RGBA(255, 160, 0, 255)
+  } else {
+    Color::RGBA(255, 255, 0, 255)
+  };
+  let aabb = collision.offset(*position);
+  match collision.slope {
+    Some(ref slope) => draw_slope(renderer, cam, &aabb, slope, draw_color),
+    None => draw_rect(renderer, cam, aabb.bottom_left(), collision.half_size * 2., draw_color),
+  }
+}
+
+fn draw_tilemap(origin: &Position, tilemap: &Tilemap, renderer: &mut Renderer, cam: &Camera) {
+  let size = Vec2::new(tilemap.tile_size, tilemap.tile_size);
+  for x in 0..tilemap.width {
+    for y in 0..tilemap.height {
+      let tile = tilemap.get_tile(x, y);
+      let aabb = tilemap.tile_aabb(x, y).offset(*origin);
+      match tile {
+        Tile::Empty => {},
+        Tile::Solid => draw_rect(renderer, cam, aabb.bottom_left(), size, Color::RGBA(200, 200, 0, 255)),
+        Tile::OneWay => draw_rect(renderer, cam, aabb.bottom_left(), size, Color::RGBA(255, 160, 0, 255)),
+        Tile::Ladder => draw_rect(renderer, cam, aabb.bottom_left(), size, Color::RGBA(140, 90, 40, 255)),
+        Tile::Water => draw_rect(renderer, cam, aabb.bottom_left(), size, Color::RGBA(40, 90, 255, 100)),
+        _ => {
+          if let Some(ref slope) = tile.slope() {
+            draw_slope(renderer, cam, &aabb, slope, Color::RGBA(200, 200, 0, 255));
+          }
+        },
+      }
+    }
+  }
 }
 
 
@@ -118,17 +196,63 @@ pub fn render_system(world: &World, renderer: &mut Renderer) {
         draw_sprite(sprite, pos, renderer, camera);
       }
     }
+    for id in &world.entities {
+      if let (Some(ref tilemap), Some(ref origin)) =
+             (world.tilemaps.get(&id), world.positions.get(&id))
+      {
+        if tilemap.layer & !world.hidden_layers == 0 {
+          continue;
+        }
+        draw_tilemap(origin, tilemap, renderer, camera);
+      }
+    }
     for id in &world.entities {
       if let (Some(ref collision), Some(ref position)) =
              (world.collisions.get(&id), world.positions.get(&id))
       {
-        draw_static(position, collision, renderer, camera, world.statics_collisions.contains(id));
+        if collision.layer & !world.hidden_layers == 0 {
+          continue;
+        }
+        let mut position = **position;
+        if let Some(crumbling) = world.crumbling.get(id) {
+          if crumbling.is_gone() {
+            continue;
+          }
+          position += crumbling.shake_offset();
+        }
+        if world.door_open(*id) {
+          // Faintly, where it closes
+          draw_rect(renderer, camera, collision.offset(position).bottom_left(), collision.half_size * 2., Color::RGBA(255, 255, 0, 40));
+          continue;
+        }
+        let touched = if collision.sensor {
+          world.signal(*id) || world.sensor_contacts.iter().any(|&(sensor, _)| sensor == *id)
+        } else {
+          world.statics_collisions.contains(id)
+        };
+        draw_static(&position, collision, renderer, camera, touched, world.liquids.contains_key(id));
+        if let Some(launcher) = world.launchers.get(id) {
+          draw_arrow(renderer, camera, position + collision.center, launcher.direction(), Color::RGBA(255, 0, 255, 255));
+        }
+      }
+    }
+    for joint in world.joints.values() {
+      if let Some((a, b)) = joint.ends(world) {
+        let color = match joint.kind {
+          JointKind::Rope => Color::RGBA(180, 140, 90, 255),
+          JointKind::Rod => Color::RGBA(220, 220, 220, 255),
+          JointKind::Spring { .. } => Color::RGBA(0, 220, 120, 255),
+        };
+        draw_line(renderer, camera, a, b, color);
       }
     }
     for id in &world.entities {
       if let (Some(ref collision), Some(ref position), Some(on_ground)) =
              (world.collisions.get(&id), world.positions.get(&id), world.groundables.get(&id))
       {
+        if collision.layer & !world.hidden_layers == 0 {
+          continue;
+        }
         draw_physics(position, collision, *on_ground, renderer, camera);
       }
     }
@@ -0,0 +1,266 @@
+extern crate sdl2;
+
+use std::time;
+use std::collections::HashMap;
+
+use self::sdl2::render::Renderer;
+
+use common::InputState;
+use components::World;
+
+// Stages run in this order every frame. FixedUpdate runs zero or more times,
+// once per step of accumulated frame time, the step comes from the world's physics settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+  Input,
+  FixedUpdate,
+  LateUpdate,
+  Render,
+}
+
+const STAGES: [Stage; 4] = [Stage::Input, Stage::FixedUpdate, Stage::LateUpdate, Stage::Render];
+
+pub struct FrameContext<'a, 'r: 'a> {
+  pub input: &'a InputState,
+  pub renderer: &'a mut Renderer<'r>,
+  pub dt_seconds: f64,
+}
+
+pub enum System<'s> {
+  // Only needs the world and a time step, this is the only kind allowed in FixedUpdate
+  World(Box<FnMut(&mut World, f64) + 's>),
+  // Needs this frame's input and renderer
+  Frame(Box<FnMut(&mut World, &mut FrameContext) + 's>),
+}
+
+pub struct Registration<'s> {
+  pub label: &'static str,
+  pub stage: Stage,
+  before: Vec<&'static str>,
+  after: Vec<&'static str>,
+  system: System<'s>,
+}
+
+impl<'s> Registration<'s> {
+  pub fn before(&mut self, label: &'static str) -> &mut Registration<'s> {
+    self.before.push(label);
+    self
+  }
+  pub fn after(&mut self, label: &'static str) -> &mut Registration<'s> {
+    self.after.push(label);
+    self
+  }
+}
+
+pub struct Schedule<'s> {
+  systems: Vec<Registration<'s>>,
+  // Indices into `systems` per stage, sorted by the before/after constraints
+  order: HashMap<Stage, Vec<usize>>,
+  dirty: bool,
+  dt_accum: time::Duration,
+}
+
+fn seconds(d: time::Duration) -> f64 {
+  d.as_secs() as f64 + (d.subsec_nanos() as f64 / 1000000000.)
+}
+
+fn duration(seconds: f64) -> time::Duration {
+  time::Duration::new(seconds.trunc() as u64, (seconds.fract() * 1000000000.) as u32)
+}
+
+impl<'s> Schedule<'s> {
+  pub fn new() -> Schedule<'s> {
+    Schedule {
+      systems: Vec::new(),
+      order: HashMap::new(),
+      dirty: true,
+      dt_accum: time::Duration::new(0, 0),
+    }
+  }
+
+  pub fn add(&mut self, stage: Stage, label: &'static str, system: System<'s>) -> &mut Registration<'s> {
+    if let (Stage::FixedUpdate, &System::Frame(_)) = (stage, &system) {
+      panic!("{} can't run in FixedUpdate, it needs the frame context", label);
+    }
+    if self.systems.iter().any(|r| r.label == label) {
+      panic!("System {} registered twice", label);
+    }
+    self.dirty = true;
+    self.systems.push(Registration {
+      label: label,
+      stage: stage,
+      before: Vec::new(),
+      after: Vec::new(),
+      system: system,
+    });
+    self.systems.last_mut().unwrap()
+  }
+
+  pub fn add_world(&mut self, stage: Stage, label: &'static str, system: fn(&mut World, f64)) -> &mut Registration<'s> {
+    self.add(stage, label, System::World(Box::new(system)))
+  }
+
+  pub fn add_frame(&mut self, stage: Stage, label: &'static str, system: fn(&mut World, &mut FrameContext)) -> &mut Registration<'s> {
+    self.add(stage, label, System::Frame(Box::new(system)))
+  }
+
+  // Topological sort of one stage. Constraints naming systems in other stages can't hold,
+  // stages always run in order, so they are reported and left out.
+  // Ties keep registration order so the result is deterministic.
+  fn sort_stage(&self, stage: Stage) -> Vec<usize> {
+    let members: Vec<usize> = (0..self.systems.len())
+      .filter(|&i| self.systems[i].stage == stage)
+      .collect();
+    let index_of = |label: &str| members.iter().cloned().find(|&i| self.systems[i].label == label);
+    let other_stage = |label: &str| self.systems.iter().find(|r| r.label == label && r.stage != stage).map(|r| r.stage);
+
+    // edges[a] contains b when a must run before b
+    let mut edges: HashMap<usize, Vec<usize>> = HashMap::new();
+    let mut incoming: HashMap<usize, usize> = members.iter().map(|&i| (i, 0)).collect();
+    for &i in &members {
+      let reg = &self.systems[i];
+      let mut add_edge = |a: usize, b: usize| {
+        edges.entry(a).or_insert_with(Vec::new).push(b);
+        *incoming.get_mut(&b).unwrap() += 1;
+      };
+      for label in &reg.before {
+        if let Some(j) = index_of(label) {
+          add_edge(i, j);
+        } else if let Some(other) = other_stage(label) {
+          println!("{} in {:?} can't run before {} in {:?}, ignoring it", reg.label, stage, label, other);
+        }
+      }
+      for label in &reg.after {
+        if let Some(j) = index_of(label) {
+          add_edge(j, i);
+        } else if let Some(other) = other_stage(label) {
+          println!("{} in {:?} can't run after {} in {:?}, ignoring it", reg.label, stage, label, other);
+        }
+      }
+    }
+
+    let mut sorted = Vec::new();
+    let mut remaining = members.clone();
+    while !remaining.is_empty() {
+      let next = remaining.iter().position(|i| incoming[i] == 0);
+      match next {
+        Some(pos) => {
+          let i = remaining.remove(pos);
+          if let Some(targets) = edges.get(&i) {
+            for t in targets {
+              *incoming.get_mut(t).unwrap() -= 1;
+            }
+          }
+          sorted.push(i);
+        },
+        None => {
+          let labels: Vec<&str> = remaining.iter().map(|&i| self.systems[i].label).collect();
+          panic!("System ordering cycle in {:?}: {:?}", stage, labels);
+        },
+      }
+    }
+    sorted
+  }
+
+  fn sort(&mut self) {
+    if !self.dirty {
+      return;
+    }
+    for stage in STAGES.iter() {
+      let sorted = self.sort_stage(*stage);
+      self.order.insert(*stage, sorted);
+    }
+    self.dirty = false;
+  }
+
+  pub fn labels(&mut self, stage: Stage) -> Vec<&'static str> {
+    self.sort();
+    self.order[&stage].iter().map(|&i| self.systems[i].label).collect()
+  }
+
+  fn run_stage(&mut self, stage: Stage, world: &mut World, ctx: &mut FrameContext) {
+    for &i in &self.order[&stage] {
+      match self.systems[i].system {
+        System::World(ref mut f) => f(world, ctx.dt_seconds),
+        System::Frame(ref mut f) => f(world, ctx),
+      }
+    }
+  }
+
+  // Runs FixedUpdate once per whole step in the accumulated time, keeping the remainder for next time
+  pub fn run_fixed(&mut self, world: &mut World, dt: time::Duration) {
+    self.sort();
+    self.dt_accum += dt;
+    let step_seconds = world.physics.step;
+    if step_seconds <= 0. {
+      return;
+    }
+    let step = duration(step_seconds);
+    let mut substeps = 0;
+    while self.dt_accum >= step {
+      if substeps == world.physics.max_substeps {
+        self.dt_accum = time::Duration::new(0, 0);
+        break;
+      }
+      for &i in &self.order[&Stage::FixedUpdate] {
+        if let System::World(ref mut f) = self.systems[i].system {
+          f(world, step_seconds);
+        }
+      }
+      self.dt_accum -= step;
+      substeps += 1;
+    }
+  }
+
+  pub fn run_frame(&mut self, world: &mut World, input: &InputState, renderer: &mut Renderer, dt: time::Duration) {
+    self.sort();
+    let mut ctx = FrameContext {
+      input: input,
+      renderer: renderer,
+      dt_seconds: seconds(dt),
+    };
+    self.run_stage(Stage::Input, world, &mut ctx);
+    self.run_fixed(world, dt);
+    self.run_stage(Stage::LateUpdate, world, &mut ctx);
+    self.run_stage(Stage::Render, world, &mut ctx);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn noop(_: &mut World, _: f64) {}
+
+  #[test]
+  fn constraints_order_stage() {
+    let mut s = Schedule::new();
+    s.add_world(Stage::FixedUpdate, "c", noop).after("b");
+    s.add_world(Stage::FixedUpdate, "a", noop);
+    s.add_world(Stage::FixedUpdate, "b", noop).after("a");
+    s.add_world(Stage::FixedUpdate, "first", noop).before("a");
+    s.add_world(Stage::LateUpdate, "late", noop).before("c");
+    assert_eq!(s.labels(Stage::FixedUpdate), vec!["first", "a", "b", "c"]);
+    assert_eq!(s.labels(Stage::LateUpdate), vec!["late"]);
+  }
+
+  #[test]
+  fn fixed_update_uses_accumulator() {
+    fn count(w: &mut World, _: f64) {
+      w.new_entity();
+    }
+    let mut s = Schedule::new();
+    s.add_world(Stage::FixedUpdate, "count", count);
+    let mut w = World::new();
+    s.run_fixed(&mut w, time::Duration::from_millis(16));
+    assert_eq!(w.entities.len(), 1);
+    s.run_fixed(&mut w, time::Duration::from_millis(16));
+    assert_eq!(w.entities.len(), 3);
+    // A long frame runs at most max_substeps and drops the rest
+    w.physics.max_substeps = 4;
+    s.run_fixed(&mut w, time::Duration::from_millis(1000));
+    assert_eq!(w.entities.len(), 7);
+    s.run_fixed(&mut w, time::Duration::from_millis(5));
+    assert_eq!(w.entities.len(), 7);
+  }
+}
@@ -2,19 +2,27 @@ extern crate sdl2;
 use self::sdl2::keyboard::Keycode;
 pub use self::sdl2::render::Renderer;
 
-use std::time;
 use std::path::Path;
 
-use common::{InputState, Vec2};
+use common::{InputState, Vec2, AABB};
 use components::{
   PlayerAction,
   CameraAction,
-  Velocity,
   World,
+  ID,
 };
-use camera::Camera;
+use camera::{Camera, Follow, camera_follow_system};
 use render;
-use physics::simulation_systems;
+use render::Sprite;
+use hierarchy::propagate_transforms;
+use physics;
+use triggers;
+use logic;
+use joints;
+use controller::{Controller, ControlInput};
+use abilities::{AbilityInput, air_jump, dash};
+use query::{QueryFilter, touches_ladder};
+use schedule::{Schedule, Stage, FrameContext};
 
 
 pub fn create_world(renderer: &mut Renderer, screen_size: Vec2) -> World {
@@ -27,6 +35,28 @@ pub fn create_world(renderer: &mut Renderer, screen_size: Vec2) -> World {
   }
 
   world.current_player = world.new_player();
+  if world.find_by_name("player").is_none() {
+    let player = world.current_player;
+    let _ = world.set_name(player, "player");
+  }
+  let player = world.current_player;
+  if let Some(start) = world.positions.get(&player).cloned() {
+    world.respawns.insert(player, start);
+  }
+
+  // The player's look hangs off the player's collider, the camera keeps up with it side to side
+  let player_sprite = world.new_entity();
+  world.positions.insert(player_sprite, Vec2::new(0., 0.));
+  world.sprites.insert(player_sprite, Sprite::new_sheet(
+    renderer,
+    "assets/animate.bmp",
+    AABB::new(Vec2::new(0., 0.), Vec2::new(1.5, 2.)),
+    6,
+  ));
+  let player = world.current_player;
+  world.attach(player_sprite, player);
+  let camera = world.current_camera;
+  world.follows.insert(camera, Follow::x(player));
 
   world.new_mover_block(Vec2::new(0., 10.), Vec2::new(20., 10.), 4.);
 
@@ -35,9 +65,19 @@ pub fn create_world(renderer: &mut Renderer, screen_size: Vec2) -> World {
   world
 }
 
+// How long Down+Jump lets the player fall through one-way platforms
+const DROP_THROUGH_TIME: f64 = 0.2;
+
 fn player_input_controller(input: &InputState, actions: &mut Vec<PlayerAction>) {
   if input.key_pressed(&Keycode::Space) {
-    actions.push(PlayerAction::Jump);
+    if input.key_down(&Keycode::Down) {
+      actions.push(PlayerAction::DropThrough);
+    } else {
+      actions.push(PlayerAction::Jump);
+    }
+  }
+  if input.key_down(&Keycode::Space) && !input.key_down(&Keycode::Down) {
+    actions.push(PlayerAction::JumpHeld);
   }
   if input.key_down(&Keycode::Left) {
     actions.push(PlayerAction::MoveLeft);
@@ -45,6 +85,18 @@ fn player_input_controller(input: &InputState, actions: &mut Vec<PlayerAction>)
   if input.key_down(&Keycode::Right) {
     actions.push(PlayerAction::MoveRight);
   }
+  if input.key_down(&Keycode::Up) {
+    actions.push(PlayerAction::LookUp);
+  }
+  if input.key_down(&Keycode::Down) {
+    actions.push(PlayerAction::LookDown);
+  }
+  if input.key_pressed(&Keycode::X) {
+    actions.push(PlayerAction::Dash);
+  }
+  if input.key_pressed(&Keycode::C) {
+    actions.push(PlayerAction::Interact);
+  }
 }
 
 fn camera_input_controller(input: &InputState, actions: &mut Vec<CameraAction>) {
@@ -66,19 +118,34 @@ fn camera_input_controller(input: &InputState, actions: &mut Vec<CameraAction>)
   }
 }
 
-fn player_update(actions: &Vec<PlayerAction>, velocity: &mut Velocity, on_ground: &bool) {
-  velocity.x = 0.;
+// Presses happen once, held movement is read again every frame
+fn is_press(action: &PlayerAction) -> bool {
+  match *action {
+    PlayerAction::Jump | PlayerAction::DropThrough | PlayerAction::Dash | PlayerAction::Interact => true,
+    _ => false,
+  }
+}
+
+fn player_update(actions: &Vec<PlayerAction>) -> AbilityInput {
+  let mut input = AbilityInput {
+    control: ControlInput::default(),
+    dash_pressed: false,
+    aim: 0.,
+  };
   for a in actions {
     match a {
-      &PlayerAction::MoveLeft => velocity.x -= 24.,
-      &PlayerAction::MoveRight => velocity.x += 24.,
-      &PlayerAction::Jump => {
-        if *on_ground {
-          velocity.y += 100.;
-        }
-      },
+      &PlayerAction::MoveLeft => input.control.walk -= 1.,
+      &PlayerAction::MoveRight => input.control.walk += 1.,
+      &PlayerAction::Jump => input.control.jump_pressed = true,
+      &PlayerAction::JumpHeld => input.control.jump_held = true,
+      &PlayerAction::Dash => input.dash_pressed = true,
+      &PlayerAction::LookUp => input.aim += 1.,
+      &PlayerAction::LookDown => input.aim -= 1.,
+      // handled by player_action_system, they're not velocity changes
+      &PlayerAction::DropThrough | &PlayerAction::Interact => {},
     }
   }
+  input
 }
 
 fn camera_update(actions: &Vec<CameraAction>, cam: &mut Camera) {
@@ -94,29 +161,287 @@ fn camera_update(actions: &Vec<CameraAction>, cam: &mut Camera) {
   }
 }
 
-pub fn run_systems(world: &mut World, input: &InputState, renderer: &mut Renderer, dt: time::Duration) -> time::Duration {
+// Input only records what the player wants. Presses wait in the queue until a fixed step
+// applies them, so jumps come out the same whatever the frame rate.
+fn player_input_system(world: &mut World, ctx: &mut FrameContext) {
   for id in &world.entities {
-    // input & update systems
     if let Some(ref mut actions) = world.player_actions.get_mut(&id) {
-      player_input_controller(input, actions);
-      if let (Some(ref mut velocity), Some(ref on_ground)) = (world.velocities.get_mut(&id), world.groundables.get(&id)) {
-        player_update(actions, velocity, on_ground);
+      actions.retain(is_press);
+      player_input_controller(ctx.input, actions);
+    }
+  }
+}
+
+// How much of the player has to be under to swim instead of walk
+const SWIM_DEPTH: f64 = 0.5;
+
+// How far below its feet the player reaches for a ladder to climb down
+const LADDER_REACH: f64 = 0.1;
+
+// Overlapping a ladder, or standing on one and wanting to climb down
+fn on_ladder(w: &World, id: ID, aim: f64) -> bool {
+  match w.get_collider_entity(id) {
+    Some((pos, coll)) => {
+      let body = coll.offset(*pos);
+      let filter = QueryFilter { layer: coll.layer, mask: coll.mask, ..QueryFilter::default() };
+      touches_ladder(w, &body, &filter)
+        || (aim < 0. && touches_ladder(w, &body.offset(Vec2::new(0., -LADDER_REACH)), &filter))
+    },
+    None => false,
+  }
+}
+
+// Players without a tuned controller get the default one.
+// Dashing, climbing and swimming take over from the controller while they last.
+fn player_action_system(world: &mut World, dt: f64) {
+  let mut players: Vec<ID> = world.player_actions.keys().cloned().collect();
+  players.sort();
+  for id in players {
+    let input = player_update(&world.player_actions[&id]);
+    let drop = world.player_actions[&id].contains(&PlayerAction::DropThrough);
+    if world.player_actions[&id].contains(&PlayerAction::Interact) {
+      world.interact(id);
+    }
+    let ladder = on_ladder(world, id, input.aim);
+    let swimming = world.submerged.get(&id).map_or(false, |s| s.fraction >= SWIM_DEPTH);
+    let grip = physics::grip(world, id);
+    if let (Some(velocity), Some(&on_ground)) = (world.velocities.get_mut(&id), world.groundables.get(&id)) {
+      let controller = world.controllers.entry(id).or_insert_with(Controller::default);
+      let mut busy = false;
+      let mut weightless = false;
+      let mut steered = false;
+      if let Some(d) = world.dashes.get_mut(&id) {
+        busy = dash(d, controller, &input, velocity, dt);
+        weightless = busy && d.suspend_gravity;
+      }
+      if !busy && controller.climb(&input.control, input.aim, ladder, on_ground, velocity) {
+        busy = true;
+        weightless = true;
+        if input.aim < 0. {
+          // Through the ladder's top, just for this step
+          world.drop_through.insert(id, 2. * dt);
+        }
+      }
+      if !busy {
+        busy = controller.swim(&input.control, swimming, velocity, dt);
+      }
+      if !busy {
+        let wall = world.wall_contacts.get(&id).cloned();
+        controller.state.grip = grip;
+        steered = controller.update(&input.control, on_ground, wall, velocity, dt);
+        if let Some(jumps) = world.air_jumps.get_mut(&id) {
+          air_jump(jumps, controller, &input.control, on_ground, velocity);
+        }
+      }
+      if weightless {
+        world.weightless.insert(id);
+      } else {
+        world.weightless.remove(&id);
+      }
+      // Dashing, climbing and swimming set the speed themselves
+      if steered || busy {
+        world.steered.insert(id);
+      } else {
+        world.steered.remove(&id);
+      }
+      if drop && on_ground {
+        world.drop_through.insert(id, DROP_THROUGH_TIME);
       }
-      actions.clear();
     }
+    if let Some(actions) = world.player_actions.get_mut(&id) {
+      actions.retain(|a| !is_press(a));
+    }
+  }
+}
 
+fn camera_input_system(world: &mut World, ctx: &mut FrameContext) {
+  for id in &world.entities {
     if let Some(ref mut actions) = world.camera_actions.get_mut(&id) {
-      camera_input_controller(input, actions);
+      camera_input_controller(ctx.input, actions);
       if let Some(ref mut camera) = world.cameras.get_mut(&id) {
         camera_update(actions, camera);
       }
       actions.clear();
     }
   }
+}
+
+// The game's systems in their stages. New systems register here (or in their module's
+// register_systems) with before/after constraints instead of editing a run loop.
+pub fn create_schedule<'s>() -> Schedule<'s> {
+  let mut schedule = Schedule::new();
+
+  schedule.add_frame(Stage::Input, "player_input", player_input_system);
+  schedule.add_frame(Stage::Input, "camera_input", camera_input_system).after("player_input");
+  schedule.add_world(Stage::FixedUpdate, "player_actions", player_action_system).before("physics");
+
+  physics::register_systems(&mut schedule);
+  triggers::register_systems(&mut schedule);
+  logic::register_systems(&mut schedule);
+  joints::register_systems(&mut schedule);
+
+  schedule.add_world(Stage::LateUpdate, "late_transforms", |w, _| propagate_transforms(w));
+  schedule.add_world(Stage::LateUpdate, "camera_follow", camera_follow_system).after("late_transforms");
+  schedule.add_frame(Stage::Render, "render", |w, ctx| render::render_system(w, ctx.renderer));
+
+  schedule
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::RefCell;
+  use std::time;
+  use prefab::Prefab;
+  use schedule::System;
+  use tilemap::{Tilemap, Tile};
+  use events::Event;
+
+  // 10ms frames holding `held`, the way the input stage would fill the actions
+  fn hold(w: &mut World, schedule: &mut Schedule, id: ID, held: &[PlayerAction], seconds: f64) {
+    for _ in 0..((seconds * 100.).round() as usize) {
+      {
+        let actions = w.player_actions.get_mut(&id).unwrap();
+        actions.retain(is_press);
+        actions.extend(held.iter().cloned());
+      }
+      schedule.run_fixed(w, time::Duration::from_millis(10));
+    }
+  }
 
-  let remainder_dt = simulation_systems(world, dt);
-  render::render_system(world, renderer);
+  struct Jump {
+    apex: f64,
+    air_time: f64,
+    distance: f64,
+  }
 
-  // return unused time, to be passed through next frame
-  remainder_dt
+  // Jumps the player from rest while holding right, driving frames of `frame_millis`
+  // the way the input stage would, and measures the arc on fixed steps
+  fn jump_arc(frame_millis: u64) -> Jump {
+    let mut w = World::new();
+    w.new_static_obstacle(Vec2::new(0., -1.), Vec2::new(1000., 2.));
+    let id = w.instantiate_prefab("player", Prefab::at(Vec2::new(0., 2.))).unwrap();
+    let step = w.physics.step;
+    let steps: RefCell<Vec<(bool, Vec2)>> = RefCell::new(Vec::new());
+    {
+      let mut schedule = create_schedule();
+      schedule.add(Stage::FixedUpdate, "probe", System::World(Box::new(|w: &mut World, _: f64| {
+        steps.borrow_mut().push((w.groundables[&id], w.positions[&id]));
+      }))).after("physics");
+      let frame = time::Duration::from_millis(frame_millis);
+      for _ in 0..(200 / frame_millis) {
+        schedule.run_fixed(&mut w, frame);
+      }
+      let takeoff = steps.borrow().len();
+      w.player_actions.get_mut(&id).unwrap().push(PlayerAction::Jump);
+      for _ in 0..(1000 / frame_millis) {
+        let actions = w.player_actions.get_mut(&id).unwrap();
+        actions.retain(is_press);
+        actions.push(PlayerAction::MoveRight);
+        actions.push(PlayerAction::JumpHeld);
+        schedule.run_fixed(&mut w, frame);
+      }
+      let steps = steps.borrow();
+      let start = steps[takeoff - 1].1;
+      let landing = (takeoff..steps.len()).find(|&i| steps[i].0).unwrap();
+      Jump {
+        apex: steps[takeoff..landing].iter().map(|s| s.1.y - start.y).fold(0., f64::max),
+        air_time: (landing - takeoff) as f64 * step,
+        distance: steps[landing].1.x - start.x,
+      }
+    }
+  }
+
+  #[test]
+  fn jumps_are_the_same_at_any_frame_rate() {
+    let reference = jump_arc(10);
+    // 100 up against 500 gravity, integrated on 10ms steps
+    assert!((reference.apex - 9.5).abs() < 1e-6);
+    assert!((reference.air_time - 0.4).abs() < 0.015);
+    // Less than full speed the whole way, it takes a few steps to get going
+    let run = 24. * reference.air_time;
+    assert!(reference.distance < run && reference.distance > run - 1.);
+    for &frame_millis in &[7, 16, 33, 50] {
+      let arc = jump_arc(frame_millis);
+      assert!((arc.apex - reference.apex).abs() < 1e-9, "apex at {}ms", frame_millis);
+      assert_eq!(arc.air_time, reference.air_time, "air time at {}ms", frame_millis);
+      assert!((arc.distance - reference.distance).abs() < 1e-9, "distance at {}ms", frame_millis);
+    }
+  }
+
+  #[test]
+  fn runs_and_stops_slower_on_ice() {
+    let stopping = |friction: f64| {
+      let mut w = World::new();
+      let floor = w.new_static_obstacle(Vec2::new(0., -1.), Vec2::new(1000., 2.));
+      w.materials.insert(floor, physics::Material { friction: friction, ..physics::Material::default() });
+      let id = w.instantiate_prefab("player", Prefab::at(Vec2::new(0., 2.))).unwrap();
+      let mut schedule = create_schedule();
+      hold(&mut w, &mut schedule, id, &[PlayerAction::MoveRight], 0.1);
+      let speed = w.velocities[&id].x;
+      hold(&mut w, &mut schedule, id, &[PlayerAction::MoveRight], 2.);
+      let start = w.positions[&id].x;
+      hold(&mut w, &mut schedule, id, &[], 2.);
+      (speed, w.positions[&id].x - start)
+    };
+    let (ground_speed, ground_stop) = stopping(20.);
+    let (ice_speed, ice_stop) = stopping(0.1);
+    assert!(ice_speed < ground_speed / 5.);
+    assert!(ice_stop > ground_stop * 5., "{} on ice, {} on the ground", ice_stop, ground_stop);
+  }
+
+  #[test]
+  fn climbs_ladders_and_stands_on_top() {
+    let mut w = World::new();
+    w.new_static_obstacle(Vec2::new(0., -1.), Vec2::new(100., 2.));
+    let mut tm = Tilemap::new(1, 4, 2.);
+    for y in 0..4 {
+      tm.set_tile(0, y, Tile::Ladder);
+    }
+    let ladder = w.new_entity();
+    w.positions.insert(ladder, Vec2::new(-1., 0.));
+    w.tilemaps.insert(ladder, tm);
+    let id = w.instantiate_prefab("player", Prefab::at(Vec2::new(0., 2.))).unwrap();
+    let mut schedule = create_schedule();
+
+    // Up to the top, which is 8 high, and standing on it after letting go
+    hold(&mut w, &mut schedule, id, &[PlayerAction::LookUp], 1.);
+    hold(&mut w, &mut schedule, id, &[], 0.5);
+    assert!(w.groundables[&id]);
+    assert!((w.positions[&id].y - 10.).abs() < 1e-6);
+
+    // Back down through the top, hanging on halfway
+    hold(&mut w, &mut schedule, id, &[PlayerAction::LookDown], 0.2);
+    let halfway = w.positions[&id].y;
+    assert!(halfway < 10. && halfway > 5.);
+    hold(&mut w, &mut schedule, id, &[], 0.5);
+    assert!(!w.groundables[&id]);
+    assert_eq!(w.positions[&id].y, halfway);
+  }
+
+  #[test]
+  fn springs_and_bumpers_launch_past_the_controller() {
+    let mut w = World::new();
+    let spring = w.instantiate_prefab("spring", Prefab::at(Vec2::new(0., 0.))).unwrap();
+    let bumper = w.instantiate_prefab("bumper", Prefab::at(Vec2::new(-20., 0.))).unwrap();
+    let id = w.instantiate_prefab("player", Prefab::at(Vec2::new(0., 3.))).unwrap();
+    let mut schedule = create_schedule();
+
+    // Holding left does nothing until the lockout is over
+    hold(&mut w, &mut schedule, id, &[PlayerAction::MoveLeft], 0.2);
+    assert!(w.events.this_frame().contains(&Event::Launched { id: id, by: spring }));
+    assert_eq!(w.velocities[&id].x, 0.);
+    assert!(w.positions[&id].y > 20.);
+    hold(&mut w, &mut schedule, id, &[PlayerAction::MoveLeft], 0.2);
+    assert!(w.velocities[&id].x < 0.);
+
+    // Bumpers send bodies back faster than they came
+    let ball = w.test_body(Vec2::new(-15., 0.), Vec2::new(-50., 0.), Vec2::new(0.5, 0.5));
+    w.physics.gravity = Vec2::new(0., 0.);
+    for _ in 0..20 {
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+    }
+    assert!(w.events.this_frame().contains(&Event::Launched { id: ball, by: bumper }));
+    assert_eq!(w.velocities[&ball].x, 75.);
+  }
 }
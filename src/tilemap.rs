@@ -9,16 +9,98 @@ use std::io::BufReader;
 use std::path::Path;
 use std::io;
 
-use common::{Vec2, Vec2u, AABB};
+use common::{Vec2, AABB, Slope};
+use layers::{LayerBits, world_layer};
+use water::Liquid;
 
 // TODO: sparse tilemap representation for large maps
 // OR: tilemap chunking
 
+// Numbered the way they're written in .lv files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tile {
+  Empty,
+  Solid,
+  // Only solid when landed on from above
+  OneWay,
+  // 45 degree ramps, rising to the right or to the left
+  SlopeUp,
+  SlopeDown,
+  // Shallow ramps rise half a tile per tile, so they come in a low and a high half
+  ShallowUpLow,
+  ShallowUpHigh,
+  ShallowDownHigh,
+  ShallowDownLow,
+  // Climbable, the top of a ladder is a one-way platform
+  Ladder,
+  // Swimmable, behaves like the tilemap's liquid
+  Water,
+}
+
+impl Tile {
+  fn from_number(n: usize) -> Tile {
+    match n {
+      0 => Tile::Empty,
+      2 => Tile::OneWay,
+      3 => Tile::SlopeUp,
+      4 => Tile::SlopeDown,
+      5 => Tile::ShallowUpLow,
+      6 => Tile::ShallowUpHigh,
+      7 => Tile::ShallowDownHigh,
+      8 => Tile::ShallowDownLow,
+      9 => Tile::Ladder,
+      10 => Tile::Water,
+      // anything else nonzero has always meant a collision
+      _ => Tile::Solid,
+    }
+  }
+
+  fn number(&self) -> usize {
+    match *self {
+      Tile::Empty => 0,
+      Tile::Solid => 1,
+      Tile::OneWay => 2,
+      Tile::SlopeUp => 3,
+      Tile::SlopeDown => 4,
+      Tile::ShallowUpLow => 5,
+      Tile::ShallowUpHigh => 6,
+      Tile::ShallowDownHigh => 7,
+      Tile::ShallowDownLow => 8,
+      Tile::Ladder => 9,
+      Tile::Water => 10,
+    }
+  }
+
+  pub fn slope(&self) -> Option<Slope> {
+    match *self {
+      Tile::SlopeUp => Some(Slope::new(0., 1.)),
+      Tile::SlopeDown => Some(Slope::new(1., 0.)),
+      Tile::ShallowUpLow => Some(Slope::new(0., 0.5)),
+      Tile::ShallowUpHigh => Some(Slope::new(0.5, 1.)),
+      Tile::ShallowDownHigh => Some(Slope::new(1., 0.5)),
+      Tile::ShallowDownLow => Some(Slope::new(0.5, 0.)),
+      _ => None,
+    }
+  }
+}
+
+// The tile data lives in the .lv file at source_path, levels only store the reference
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Tilemap {
-  pub appearances: DMatrix<u32>,
-  pub collisions: DMatrix<bool>,
+  pub source_path: String,
+  // All tiles share the map's layer and collide with every layer
+  #[serde(default="world_layer")]
+  pub layer: LayerBits,
+  // For all of the map's water tiles
+  #[serde(default)]
+  pub liquid: Liquid,
+  #[serde(skip)]
+  pub tiles: Option<DMatrix<Tile>>,
+  #[serde(skip)]
   pub tile_size: f64,
+  #[serde(skip)]
   pub width: usize,
+  #[serde(skip)]
   pub height: usize,
 }
 
@@ -26,76 +108,95 @@ pub struct Tilemap {
 impl Tilemap {
   pub fn new(width: usize, height: usize, tile_size: f64) -> Tilemap {
     Tilemap {
+      source_path: String::new(),
+      layer: world_layer(),
+      liquid: Liquid::default(),
       tile_size: tile_size,
       width: width,
       height: height,
-      appearances: DMatrix::from_element(height, width, 0),
-      collisions: DMatrix::from_element(height, width, false),
+      tiles: Some(DMatrix::from_element(height, width, Tile::Empty)),
     }
   }
 
   pub fn from_file(p: &Path) -> Result<Tilemap, String> {
-    let file = File::open(p).unwrap();
-    let reader = BufReader::new(&file);
+    let file = File::open(p).map_err(|e| format!("{}", e))?;
+    let mut tiles = Tilemap::read(BufReader::new(&file))
+      .map_err(|e| format!("{}: {}", p.display(), e))?;
+    tiles.source_path = p.to_string_lossy().into_owned();
+    Ok(tiles)
+  }
 
+  fn read<R: BufRead>(reader: R) -> Result<Tilemap, String> {
     // read one line, gives width, height, tile_size
     // then, read lines, number of tokens `width`
     // read `height` lines
 
     let mut got_rows = 0;
     let mut printed_warning = false;
-    let mut tiles = Tilemap::new(0, 0, 0.);
+    let mut tiles = None;
 
     for (idx, line) in reader.lines().enumerate() {
-      let l = line.unwrap();
+      let l = line.map_err(|e| format!("{}", e))?;
       let tokens: Vec<&str> = l.split_whitespace().collect();
       if idx == 0 {
-        let width = tokens[0].parse().unwrap();
-        let height = tokens[1].parse().unwrap();
-        tiles = Tilemap {
-          width: width,
-          height: height,
-          tile_size: tokens[2].parse().unwrap(),
-          appearances: DMatrix::from_element(height, width, 0),
-          collisions: DMatrix::from_element(height, width, false),
-        };
-        println!("Got TM desc: {} {} {}", width, height, tiles.tile_size);
+        if tokens.len() < 3 {
+          return Err(format!("Expected width, height and tile size, got {:?}", l));
+        }
+        let width = tokens[0].parse().map_err(|_| format!("Bad width {}", tokens[0]))?;
+        let height = tokens[1].parse().map_err(|_| format!("Bad height {}", tokens[1]))?;
+        let tile_size = tokens[2].parse().map_err(|_| format!("Bad tile size {}", tokens[2]))?;
+        println!("Got TM desc: {} {} {}", width, height, tile_size);
+        tiles = Some(Tilemap::new(width, height, tile_size));
         continue;
       }
+      let tiles = tiles.as_mut().unwrap();
 
       match tokens.len() {
         0 => continue,
-        num if num < tiles.width => panic!("Row length didn't match level desc"),
-        num if num >= tiles.width => {
+        num if num < tiles.width => return Err(format!("Row {} has {} tiles, expected {}", idx, num, tiles.width)),
+        num => {
+          if got_rows == tiles.height {
+            return Err(format!("More than {} rows", tiles.height));
+          }
           if num > tiles.width && !printed_warning {
             println!("Warning: level width truncated");
             printed_warning = true;
           }
-          let values = tokens.iter();
+          let row = tiles.height - got_rows - 1;
+          let values = tokens.iter().take(tiles.width);
           for (index, &v) in values.enumerate() {
-            let tile_type: usize = v.parse().unwrap();
-            if tile_type > 0 {
-              tiles.collisions[(tiles.height - got_rows - 1, index)] = true;
-            }
+            let tile_type: usize = v.parse().map_err(|_| format!("Bad tile {} on row {}", v, idx))?;
+            tiles.set_tile(index, row, Tile::from_number(tile_type));
           }
           got_rows += 1;
         },
-        default => panic!("Line too short, {}", default),
       }
     }
-    assert!(got_rows == tiles.height);
+    let tiles = tiles.ok_or("Empty tilemap file".to_owned())?;
+    if got_rows != tiles.height {
+      return Err(format!("Got {} rows, expected {}", got_rows, tiles.height));
+    }
 
     Ok(tiles)
   }
 
+  // Post-deserialize initialization, like Sprite::reload_assets
+  pub fn reload_assets(&mut self) -> Result<(), String> {
+    let mut loaded = Tilemap::from_file(Path::new(&self.source_path))?;
+    loaded.layer = self.layer;
+    loaded.liquid = self.liquid;
+    *self = loaded;
+    Ok(())
+  }
+
   pub fn save(&self, p: &Path) -> Result<String, io::Error> {
     let mut file = File::create(p)?;
 
     file.write_fmt(format_args!("{} {} {}\n", self.width, self.height, self.tile_size))?;
 
-    for y in (0..self.collisions.nrows()).rev() {
-      for val in self.collisions.row(y).iter() {
-        file.write_all(if *val { b"1 " } else { b"0 " })?;
+    for y in (0..self.height).rev() {
+      for x in 0..self.width {
+        file.write_fmt(format_args!("{} ", self.get_tile(x, y).number()))?;
       }
       file.write_all(b"\n")?;
     }
@@ -123,27 +224,76 @@ impl Tilemap {
     Some((x as usize, y as usize))
   }
 
-  fn get_tile(&self, x: usize, y: usize) -> bool {
+  pub fn get_tile(&self, x: usize, y: usize) -> Tile {
     if x >= self.width || y >= self.height {
-      return false;
+      return Tile::Empty;
+    }
+    match self.tiles {
+      Some(ref tiles) => tiles[(y, x)],
+      None => Tile::Empty,
     }
-    self.collisions[(y, x)]
   }
 
-  pub fn intersects_box(&self, aabb: &AABB) -> Vec<Vec2u> {
+  pub fn set_tile(&mut self, x: usize, y: usize, tile: Tile) {
+    if x >= self.width || y >= self.height {
+      return;
+    }
+    if let Some(ref mut tiles) = self.tiles {
+      tiles[(y, x)] = tile;
+    }
+  }
+
+  // Box of a tile, in the tilemap's local coordinates
+  pub fn tile_aabb(&self, x: usize, y: usize) -> AABB {
+    let half = Vec2::new(self.tile_size, self.tile_size) / 2.;
+    AABB::new(Vec2::new(x as f64 * self.tile_size, y as f64 * self.tile_size) + half, half)
+  }
+
+  // Non-empty tiles touching a box given in local coordinates, clamped to the map
+  pub fn intersects_box(&self, aabb: &AABB) -> Vec<(usize, usize, Tile)> {
     let mut isects = Vec::new();
-    let obl = self.tile_for(aabb.bottom_left());
-    let otr = self.tile_for(aabb.top_right());
-    // TODO: this is wrong on the edges, needs to clamp
-    if let (Some(bl), Some(tr)) = (obl, otr) {
-      for x in bl.0..(tr.0+1) {
-        for y in bl.1..(tr.1+1) {
-          if self.get_tile(x, y) {
-            isects.push(Vec2u::new(x, y));
-          }
+    if self.width == 0 || self.height == 0 || self.tile_size <= 0. {
+      return isects;
+    }
+    let bl = aabb.bottom_left() / self.tile_size;
+    let tr = aabb.top_right() / self.tile_size;
+    let clamp = |v: f64, max: usize| v.floor().max(0.).min(max as f64 - 1.) as usize;
+    if tr.x < 0. || tr.y < 0. || bl.x >= self.width as f64 || bl.y >= self.height as f64 {
+      return isects;
+    }
+    for x in clamp(bl.x, self.width)..(clamp(tr.x, self.width) + 1) {
+      for y in clamp(bl.y, self.height)..(clamp(tr.y, self.height) + 1) {
+        let tile = self.get_tile(x, y);
+        if tile != Tile::Empty {
+          isects.push((x, y, tile));
         }
       }
     }
     isects
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn malformed_files_are_errors() {
+    assert!(Tilemap::read(&b"2 2 1.\n0 1\n1 1\n"[..]).is_ok());
+    for bad in &["", "2 x 1.", "2 2 1.\n0 1\n", "2 2 1.\n0\n1 1\n", "2 2 1.\n0 1\n1 a\n", "2 1 1.\n0 1\n1 1\n"] {
+      assert!(Tilemap::read(bad.as_bytes()).is_err(), "{:?}", bad);
+    }
+  }
+
+  #[test]
+  fn box_query_clamps_to_map() {
+    let mut tm = Tilemap::new(4, 3, 2.);
+    tm.set_tile(0, 0, Tile::Solid);
+    tm.set_tile(3, 2, Tile::OneWay);
+    let all = tm.intersects_box(&AABB::new(Vec2::new(4., 3.), Vec2::new(100., 100.)));
+    assert_eq!(all, vec![(0, 0, Tile::Solid), (3, 2, Tile::OneWay)]);
+    let none = tm.intersects_box(&AABB::new(Vec2::new(-10., -10.), Vec2::new(1., 1.)));
+    assert!(none.is_empty());
+    assert_eq!(tm.tile_aabb(3, 2).center, Vec2::new(7., 5.));
+  }
+}
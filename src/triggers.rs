@@ -0,0 +1,213 @@
+use std::collections::HashSet;
+
+use common::Vec2;
+use components::{World, ID};
+use events::Event;
+use schedule::{Schedule, Stage};
+use abilities::Ability;
+use camera::Follow;
+
+// Level logic run by a sensor when a body enters it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Trigger {
+  // Bodies that enter respawn where they entered from now on
+  Checkpoint,
+  // Sends bodies back to their respawn point, or to where they started
+  Hazard,
+  // Holds the camera on the zone while the player is inside
+  CameraZone,
+  // Loads assets/<level>.air when the player enters
+  LevelExit(String),
+  // Gives the player an ability and goes away
+  Grant(Ability),
+  // Climbable, the top is a one-way platform. Handled by the player's controller.
+  Ladder,
+}
+
+// Sensor/body pairs overlapping right now, every body with a solid collider is checked
+fn sensor_overlaps(w: &World) -> HashSet<(ID, ID)> {
+  let mut overlaps = HashSet::new();
+  for sensor in &w.entities {
+    let (sensor_box, sensor_coll) = match w.get_collider_entity(*sensor) {
+      Some((pos, coll)) if coll.sensor => (coll.offset(*pos), coll),
+      _ => continue,
+    };
+    for id in &w.entities {
+      if !w.velocities.contains_key(id) {
+        continue;
+      }
+      if let Some((pos, coll)) = w.get_collider_entity(*id) {
+        if !coll.sensor && coll.interacts_with(sensor_coll) && sensor_box.intersect(&coll.offset(*pos)).is_some() {
+          overlaps.insert((*sensor, *id));
+        }
+      }
+    }
+  }
+  overlaps
+}
+
+// Publishes enter/stay/exit for every pair each fixed step
+fn sensor_system(w: &mut World, _: f64) {
+  let overlaps = sensor_overlaps(w);
+  let mut entered: Vec<&(ID, ID)> = overlaps.difference(&w.sensor_contacts).collect();
+  let mut stayed: Vec<&(ID, ID)> = overlaps.intersection(&w.sensor_contacts).collect();
+  let mut exited: Vec<&(ID, ID)> = w.sensor_contacts.difference(&overlaps).collect();
+  entered.sort();
+  stayed.sort();
+  exited.sort();
+  let mut events = Vec::new();
+  for &&(sensor, id) in &entered {
+    events.push(Event::TriggerEnter { sensor: sensor, id: id });
+  }
+  for &&(sensor, id) in &stayed {
+    events.push(Event::TriggerStay { sensor: sensor, id: id });
+  }
+  for &&(sensor, id) in &exited {
+    events.push(Event::TriggerExit { sensor: sensor, id: id });
+  }
+  for event in events {
+    w.events.publish(event);
+  }
+  w.sensor_contacts = overlaps;
+}
+
+impl World {
+  // Back to the last checkpoint, or to the spawn point of bodies that never reached one.
+  // Only bodies with neither, which never moved, are removed.
+  pub fn respawn(&mut self, id: ID) {
+    let point = match self.respawns.get(&id).cloned() {
+      Some(pos) => Some(pos),
+      None => self.spawn_point(id),
+    };
+    match point {
+      Some(pos) => {
+        self.positions.insert(id, pos);
+        self.velocities.insert(id, Vec2::new(0., 0.));
+        self.events.publish(Event::Respawned { id: id, at: pos });
+      },
+      None => {
+        println!("{} has nowhere to respawn, removing it", id);
+        self.delete_entity(id);
+        self.events.publish(Event::Removed { id: id });
+      },
+    }
+  }
+}
+
+// Runs the level logic for this frame's enter/exit events.
+// Once per frame, so it sees the events of every fixed step that ran.
+fn trigger_system(w: &mut World, _: f64) {
+  let events: Vec<Event> = w.events.this_frame().to_vec();
+  for event in events {
+    match event {
+      Event::TriggerEnter { sensor, id } => {
+        let trigger = match w.triggers.get(&sensor) {
+          Some(trigger) => trigger.clone(),
+          None => continue,
+        };
+        let is_player = id == w.current_player;
+        match trigger {
+          Trigger::Checkpoint => {
+            if let Some(pos) = w.positions.get(&id).cloned() {
+              w.respawns.insert(id, pos);
+            }
+          },
+          Trigger::Hazard => w.respawn(id),
+          Trigger::CameraZone if is_player => {
+            let camera = w.current_camera;
+            if let Some(follow) = w.follows.get_mut(&camera) {
+              follow.paused = true;
+            }
+            if let (Some(pos), Some(cam)) = (w.positions.get(&sensor).cloned(), w.cameras.get_mut(&camera)) {
+              cam.pos = pos;
+            }
+          },
+          Trigger::LevelExit(ref level) if is_player => {
+            w.next_level = Some(level.clone());
+          },
+          Trigger::Grant(ref ability) if is_player => {
+            w.grant(id, ability.clone());
+            w.delete_entity(sensor);
+          },
+          _ => {},
+        }
+      },
+      Event::TriggerExit { sensor, id } => {
+        if id == w.current_player && w.triggers.get(&sensor) == Some(&Trigger::CameraZone) {
+          // Back to following the player
+          let camera = w.current_camera;
+          if let (Some(pos), Some(cam)) = (w.positions.get(&id).cloned(), w.cameras.get_mut(&camera)) {
+            cam.pos = pos;
+          }
+          w.follows.entry(camera).or_insert(Follow::x(id)).paused = false;
+        }
+      },
+      _ => {},
+    }
+  }
+}
+
+pub fn register_systems(schedule: &mut Schedule) {
+  schedule.add_world(Stage::FixedUpdate, "sensors", sensor_system).after("physics");
+  schedule.add_world(Stage::LateUpdate, "triggers", trigger_system).before("late_transforms");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time;
+  use physics;
+  use prefab::Prefab;
+
+  #[test]
+  fn sensors_report_pairs_and_never_push() {
+    let mut w = World::new();
+    let zone = w.new_sensor(Vec2::new(10., 1.), Vec2::new(2., 2.));
+    let body = w.test_body(Vec2::new(0., 1.), Vec2::new(100., 0.), Vec2::new(1., 1.));
+    w.physics.gravity = Vec2::new(0., 0.);
+    let mut schedule = Schedule::new();
+    physics::register_systems(&mut schedule);
+    register_systems(&mut schedule);
+
+    let mut seen = Vec::new();
+    for _ in 0..20 {
+      w.velocities.get_mut(&body).unwrap().x = 100.;
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+      for event in w.events.this_frame() {
+        match *event {
+          Event::TriggerEnter { sensor, .. } if sensor == zone => seen.push("enter"),
+          Event::TriggerStay { .. } => seen.push("stay"),
+          Event::TriggerExit { .. } => seen.push("exit"),
+          _ => {},
+        }
+      }
+      w.events.end_frame();
+    }
+    // Overlapping while the body is within 2 of the sensor, it moves 1 per step
+    assert_eq!(seen[0], "enter");
+    assert_eq!(seen.iter().filter(|e| **e == "stay").count(), 2);
+    assert_eq!(seen.last(), Some(&"exit"));
+    assert!(w.sensor_contacts.is_empty());
+    assert_eq!(w.velocities[&body].x, 100.);
+  }
+
+  #[test]
+  fn players_without_a_checkpoint_go_back_to_the_start() {
+    let mut w = World::new();
+    let player = w.new_player();
+    w.current_player = player;
+    let start = w.positions[&player];
+    w.respawns.clear();
+    w.positions.insert(player, Vec2::new(0., -100.));
+    w.respawn(player);
+    assert_eq!(w.positions[&player], start);
+
+    // Placed by the level
+    let placed = w.instantiate_prefab("player", Prefab::at(Vec2::new(20., 30.))).unwrap();
+    w.current_player = placed;
+    w.positions.insert(placed, Vec2::new(0., -100.));
+    w.respawn(placed);
+    assert_eq!(w.positions[&placed], Vec2::new(20., 30.));
+    assert!(w.entities.contains(&placed));
+  }
+}
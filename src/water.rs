@@ -0,0 +1,125 @@
+use common::AABB;
+use components::{World, ID};
+use query::QueryFilter;
+use layers::{ALL_LAYERS, interacts};
+use tilemap::Tile;
+
+// How a body of water (or anything else you can swim in) treats what's inside it.
+// Sensors with a liquid are water volumes, tilemaps use theirs for their water tiles.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Liquid {
+  // Rate (per second) at which speed dies out while fully under
+  pub drag: f64,
+  // Scales the body's gravity while fully under
+  pub gravity_scale: f64,
+  // Upward push while fully under, in multiples of gravity. Above the gravity left over
+  // from gravity_scale, bodies float up.
+  pub buoyancy: f64,
+  // Publish Splash events when bodies go in or come out
+  pub splash: bool,
+}
+
+impl Default for Liquid {
+  fn default() -> Liquid {
+    Liquid {
+      drag: 4.,
+      gravity_scale: 0.3,
+      buoyancy: 0.,
+      splash: true,
+    }
+  }
+}
+
+impl Liquid {
+  // Gravity scale for a body with `gravity_scale` that's `fraction` under
+  pub fn gravity_scale_for(&self, gravity_scale: f64, fraction: f64) -> f64 {
+    gravity_scale * (1. - fraction * (1. - self.gravity_scale)) - self.buoyancy * fraction
+  }
+}
+
+// The liquid a box is deepest in, and how much of the box is under
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Submersion {
+  pub id: ID,
+  pub liquid: Liquid,
+  pub fraction: f64,
+}
+
+fn overlap_area(a: &AABB, b: &AABB) -> f64 {
+  let (a0, a1, b0, b1) = (a.bottom_left(), a.top_right(), b.bottom_left(), b.top_right());
+  let w = a1.x.min(b1.x) - a0.x.max(b0.x);
+  let h = a1.y.min(b1.y) - a0.y.max(b0.y);
+  if w > 0. && h > 0. { w * h } else { 0. }
+}
+
+pub fn submersion(w: &World, aabb: &AABB, filter: &QueryFilter) -> Option<Submersion> {
+  let area = aabb.half_size.x * aabb.half_size.y * 4.;
+  if area <= 0. {
+    return None;
+  }
+  let mut deepest: Option<Submersion> = None;
+  for id in &w.entities {
+    let mut under = 0.;
+    let mut liquid = None;
+    if let (Some(l), Some((pos, coll))) = (w.liquids.get(id), w.get_collider_entity(*id)) {
+      if interacts(filter.layer, filter.mask, coll.layer, coll.mask) {
+        under = overlap_area(aabb, &coll.offset(*pos));
+        liquid = Some(*l);
+      }
+    }
+    if let (Some(tilemap), Some(origin)) = (w.tilemaps.get(id), w.positions.get(id)) {
+      if interacts(filter.layer, filter.mask, tilemap.layer, ALL_LAYERS) {
+        let local = aabb.offset(-origin);
+        under = tilemap.intersects_box(&local).iter()
+          .filter(|&&(_, _, tile)| tile == Tile::Water)
+          .map(|&(x, y, _)| overlap_area(&local, &tilemap.tile_aabb(x, y)))
+          .sum();
+        liquid = Some(tilemap.liquid);
+      }
+    }
+    if let Some(liquid) = liquid {
+      let fraction = (under / area).min(1.);
+      if fraction > 0. && deepest.map_or(true, |d| fraction > d.fraction) {
+        deepest = Some(Submersion { id: *id, liquid: liquid, fraction: fraction });
+      }
+    }
+  }
+  deepest
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time;
+  use common::Vec2;
+  use events::Event;
+  use physics;
+  use schedule::Schedule;
+
+  #[test]
+  fn bodies_splash_in_and_float() {
+    let mut w = World::new();
+    let pool = w.new_water(Vec2::new(0., 0.), Vec2::new(20., 10.));
+    w.liquids.get_mut(&pool).unwrap().buoyancy = 2.;
+    let body = w.test_body(Vec2::new(0., 10.), Vec2::new(0., 0.), Vec2::new(1., 1.));
+    let mut schedule = Schedule::new();
+    physics::register_systems(&mut schedule);
+
+    let mut splashes = Vec::new();
+    for _ in 0..1000 {
+      schedule.run_fixed(&mut w, time::Duration::from_millis(10));
+      for event in w.events.this_frame() {
+        if let Event::Splash { liquid, entering, .. } = *event {
+          assert_eq!(liquid, pool);
+          splashes.push(entering);
+        }
+      }
+      w.events.end_frame();
+    }
+    assert_eq!(splashes.first(), Some(&true));
+    // Settles where buoyancy cancels the gravity left over, 1 / 2.7 of the way under
+    let under = w.submerged[&body].fraction;
+    assert!((under - 1. / 2.7).abs() < 0.02, "{}", under);
+  }
+}